@@ -0,0 +1,131 @@
+use std::io;
+use std::time::{Duration, Instant};
+
+/// A link that carries raw IPv4 packets to and from the network, and that an `Interface`
+/// runs its TCP stack over.
+pub trait PacketDevice: Send + Sync {
+    /// Sends the IP packet in `packet`.
+    fn send(&self, packet: &[u8]) -> io::Result<usize>;
+
+    /// Receives one IP packet into `buf` and returns its length.
+    ///
+    /// Waits at most `timeout` for a packet to arrive (or forever if it is `None`), and
+    /// returns an error of kind `WouldBlock` if none did.
+    fn recv(&self, buf: &mut [u8], timeout: Option<Duration>) -> io::Result<usize>;
+
+    /// The current time on the clock that `recv` timeouts are measured against, and that
+    /// the stack's timers run on.
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    /// The largest IP packet the link carries. An `Interface` asks once, when it is created
+    /// over the device.
+    fn mtu(&self) -> usize {
+        DEFAULT_MTU
+    }
+
+    /// Whether `wake` works. An `Interface` over a device that can't be woken never waits in
+    /// `recv` for long, so that it notices timers armed by other threads without much delay.
+    fn can_wake(&self) -> bool {
+        false
+    }
+
+    /// Makes a thread blocked in `recv`, or else the next one to call it, return an error of
+    /// kind `WouldBlock` right away.
+    fn wake(&self) {}
+}
+
+// Ethernet's, which is what most links (and a freshly created tun0) use
+const DEFAULT_MTU: usize = 1500;
+
+/// A TUN device opened without packet information, so that it yields bare IP packets.
+impl PacketDevice for tun_tap::Iface {
+    fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        tun_tap::Iface::send(self, packet)
+    }
+
+    fn recv(&self, buf: &mut [u8], timeout: Option<Duration>) -> io::Result<usize> {
+        use std::os::unix::io::AsRawFd;
+        let mut pfd = libc::pollfd {
+            fd: self.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // round up so that we never wake up just before a deadline
+        let ms = timeout.map_or(-1, |timeout| {
+            let ms = timeout.as_micros().div_ceil(1000);
+            std::cmp::min(ms, libc::c_int::MAX as u128) as libc::c_int
+        });
+        let n = unsafe { libc::poll(&mut pfd, 1, ms) };
+        if n < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            return Err(e);
+        }
+        if n == 0 {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        tun_tap::Iface::recv(self, buf)
+    }
+
+    fn mtu(&self) -> usize {
+        // SIOCGIFMTU wants a socket, not the tun descriptor
+        let mut req: libc::ifreq = unsafe { std::mem::zeroed() };
+        for (dst, &src) in req.ifr_name.iter_mut().zip(self.name().as_bytes()) {
+            *dst = src as libc::c_char;
+        }
+        let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+        if fd < 0 {
+            return DEFAULT_MTU;
+        }
+        let r = unsafe { libc::ioctl(fd, libc::SIOCGIFMTU, &mut req) };
+        unsafe { libc::close(fd) };
+        if r < 0 {
+            return DEFAULT_MTU;
+        }
+        unsafe { req.ifr_ifru.ifru_mtu as usize }
+    }
+}
+
+/// A device whose MTU is looked up once, when the `Interface` is created, rather than
+/// every time a connection is set up.
+pub(crate) struct FixedMtu<D> {
+    device: D,
+    mtu: usize,
+}
+
+impl<D: PacketDevice> FixedMtu<D> {
+    pub(crate) fn new(device: D) -> Self {
+        let mtu = device.mtu();
+        FixedMtu { device, mtu }
+    }
+}
+
+impl<D: PacketDevice> PacketDevice for FixedMtu<D> {
+    fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        self.device.send(packet)
+    }
+
+    fn recv(&self, buf: &mut [u8], timeout: Option<Duration>) -> io::Result<usize> {
+        self.device.recv(buf, timeout)
+    }
+
+    fn now(&self) -> Instant {
+        self.device.now()
+    }
+
+    fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    fn can_wake(&self) -> bool {
+        self.device.can_wake()
+    }
+
+    fn wake(&self) {
+        self.device.wake()
+    }
+}
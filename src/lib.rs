@@ -1,20 +1,36 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 use::std::io::prelude::*;
 use::std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
 use std::sync::{Arc, Mutex, Condvar};
 use std::thread;
+use std::time::Duration;
 
 use tcp::Quad;
 
+pub use device::PacketDevice;
+pub use tcp::{
+    Config, CongestionControl, CongestionWindow, Cubic, IssGenerator, KeyedIss, NewReno,
+};
+
+mod device;
+pub mod sim;
 mod tcp;
 
-const SENDQUEUE_SIZE: usize = 1024; 
+// the address `run.sh` leaves free for us on tun0's 192.168.0.0/24
+const DEFAULT_ADDR: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 2);
+
+// ports handed out to actively opened connections (RFC 6335 dynamic range)
+const EPHEMERAL_PORTS: std::ops::RangeInclusive<u16> = 49152..=65535;
 
-#[derive(Default)]
 struct Foobar{
+    nic: Box<dyn PacketDevice>,
+    addr: Ipv4Addr,
     manager: Mutex<ConnectionManager>,
     pending_var: Condvar,
-    rcv_var: Condvar
+    rcv_var: Condvar,
+    snd_var: Condvar,
+    est_var: Condvar
 }
 
 type InterfaceHandle = Arc<Foobar>;
@@ -50,99 +66,398 @@ pub struct TcpStream{
 //     },
 // }
 
-#[derive(Default)]
 struct ConnectionManager {
     terminate: bool,
     connections: HashMap<tcp::Quad, tcp::Connection>,
-    pending: HashMap<u16, VecDeque<Quad>>,
+    pending: HashMap<u16, Backlog>,
+    next_port: u16,
+    config: tcp::Config,
+    iss: Box<dyn IssGenerator>,
+    cookies: tcp::SynCookies,
+    // why the packet loop gave up, if it did
+    failed: Option<io::ErrorKind>,
+}
+
+impl Default for ConnectionManager {
+    fn default() -> Self {
+        ConnectionManager {
+            terminate: false,
+            connections: HashMap::new(),
+            pending: HashMap::new(),
+            next_port: 0,
+            config: tcp::Config::default(),
+            // keyed afresh for every Interface
+            iss: Box::new(KeyedIss::new()),
+            cookies: tcp::SynCookies::new(),
+            failed: None,
+        }
+    }
+}
+
+/// The connections a listening port holds on to until they are accepted.
+#[derive(Default)]
+struct Backlog {
+    // established connections for accept to hand out, oldest first
+    queue: VecDeque<Quad>,
+    // connections to this port still waiting for the peer's ACK, which only join `queue`
+    // once it arrives
+    half_open: HashSet<Quad>,
+    // when a SYN was last answered with a cookie because `half_open` was full
+    overflowed_at: Option<std::time::Instant>,
+}
+
+impl Backlog {
+    /// Moves the connection on `quad` from `half_open` to `queue` once its handshake is over,
+    /// and returns whether it did. A connection that died half-open is left for the reaper.
+    fn update(&mut self, quad: &Quad, c: &mut tcp::Connection) -> bool {
+        if c.is_half_open() || !self.half_open.remove(quad) {
+            return false;
+        }
+        if c.is_closed() {
+            // nobody will ever accept it
+            c.detached = true;
+            return false;
+        }
+        self.queue.push_back(*quad);
+        true
+    }
+
+    /// Whether a SYN may have been answered with a cookie that is still valid, which is the
+    /// only time ACKs are worth checking for one (like Linux's `tcp_synq_no_recent_overflow`).
+    /// Otherwise every stray ACK would be a guess at the cookie's 24-bit MAC.
+    fn expects_cookies(&self, now: std::time::Instant) -> bool {
+        self.overflowed_at
+            .is_some_and(|at| now.saturating_duration_since(at) < tcp::SynCookies::LIFETIME)
+    }
 }
 
-fn packet_loop(mut nic: tun_tap::Iface, ih: InterfaceHandle) -> io::Result<()>{
-    let mut buf = [0u8; 1504];
+/// Asks `generator` for the initial sequence number of a new connection on `quad`.
+fn iss_for(generator: &mut dyn IssGenerator, quad: &Quad, now: std::time::Instant) -> u32 {
+    let local = SocketAddrV4::new(quad.dst.0, quad.dst.1);
+    let remote = SocketAddrV4::new(quad.src.0, quad.src.1);
+    generator.iss(local, remote, now)
+}
+
+impl ConnectionManager {
+    /// The connection on `quad`, or a `ConnectionAborted` error if it has gone away.
+    fn connection(&mut self, quad: &Quad) -> io::Result<&mut tcp::Connection> {
+        self.connections.get_mut(quad).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "stream was terminated unexpectedly",
+            )
+        })
+    }
+
+    /// Picks a local port for connecting to `remote` that doesn't clash with a listener or
+    /// with an existing connection to the same peer.
+    fn ephemeral_port(&mut self, local: Ipv4Addr, remote: (Ipv4Addr, u16)) -> io::Result<u16> {
+        let nports = EPHEMERAL_PORTS.end() - EPHEMERAL_PORTS.start() + 1;
+        for _ in 0..nports {
+            if !EPHEMERAL_PORTS.contains(&self.next_port) {
+                self.next_port = *EPHEMERAL_PORTS.start();
+            }
+            let port = self.next_port;
+            self.next_port = self.next_port.wrapping_add(1);
+            let quad = Quad { src: remote, dst: (local, port) };
+            if !self.pending.contains_key(&port) && !self.connections.contains_key(&quad) {
+                return Ok(port);
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            "no ephemeral ports available"
+        ))
+    }
+}
+
+// longest packet_loop sleeps on a device that can't be woken, or while a dropped Interface
+// waits for its last streams and listeners to go
+const MAX_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+fn packet_loop(ih: InterfaceHandle) -> io::Result<()>{
+    let nic = &*ih.nic;
+    let mut buf = vec![0u8; nic.mtu()];
     loop {
-        // TODO: set a timeout for this recv for TCP timers or ConnectionManager::terminate
-
-        let nbytes = nic.recv(&mut buf[..])?;
-
-        // TODO: if self.terminate && Arc.get_stro
-
-        // let eth_proto = u16::from_be_bytes([buf[2], buf[3]]);
-        // if eth_proto != 0x0800 {
-        //     // not ipv4
-        //     continue;
-        // }
-
-        match etherparse::Ipv4HeaderSlice::from_slice(&buf[..nbytes]){
-            Ok(iph) => {
-                let src = iph.source_addr();
-                let dst = iph.destination_addr();
-                if iph.protocol() != 0x06 {
-                    eprintln!("BAD PROTOCOL");
-                    // not tcp
-                    continue;
+        let timeout = {
+            let cm = ih.manager.lock().unwrap();
+            if cm.terminate && Arc::strong_count(&ih) == 1 {
+                // the Interface and every stream and listener are gone
+                return Ok(());
+            }
+            let now = nic.now();
+            let next = cm.connections
+                .values()
+                .filter_map(|c| c.next_deadline())
+                .min()
+                .map(|deadline| deadline.saturating_duration_since(now));
+            if nic.can_wake() && !cm.terminate {
+                // whoever arms an earlier timer wakes us
+                next
+            } else {
+                Some(next.map_or(MAX_POLL_INTERVAL, |t| std::cmp::min(t, MAX_POLL_INTERVAL)))
+            }
+        };
+
+        match nic.recv(&mut buf[..], timeout) {
+            Ok(nbytes) => {
+                if let Err(e) = on_datagram(&ih, &buf[..nbytes]) {
+                    // the peer will try again
+                    eprintln!("failed to answer a packet: {}", e);
                 }
-                match etherparse::TcpHeaderSlice::from_slice(
-                    &buf[iph.slice().len()..nbytes]) {
-                    Ok(tcph) => {
-                        use std::collections::hash_map::Entry; 
-                        let datai = iph.slice().len() + tcph.slice().len();
-                        let mut cmg = ih.manager.lock().unwrap();
-                        let mut cm = &mut *cmg;
-                        let q = tcp::Quad{
-                            src: (src, tcph.source_port()),
-                            dst: (dst, tcph.destination_port())
-                        };
-                        match cm.connections.entry(q){
-                            Entry::Occupied(mut c) 
-                            => {
-                                let a =  c.get_mut().on_packet(
-                                    &mut nic, 
-                                    iph, 
-                                    tcph, 
-                                    &buf[datai..nbytes]
-                                )?;
-                                // TODO: compare before/after
-                                drop(cmg);
-                                if a.contains(tcp::Available::READ) {
-                                    ih.rcv_var.notify_all()
-                                }
-                                if a.contains(tcp::Available::WRITE) {
-                                    ih.rcv_var.notify_all()
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => {
+                fail_all(&ih, e.kind());
+                return Err(e);
+            }
+        }
+
+        on_tick(&ih);
+    }
+}
+
+/// Fails every connection and blocked call once the device is gone for good.
+fn fail_all(ih: &InterfaceHandle, kind: io::ErrorKind) {
+    let mut cm = ih.manager.lock().unwrap();
+    cm.failed = Some(kind);
+    for c in cm.connections.values_mut() {
+        c.error.get_or_insert(kind);
+    }
+    drop(cm);
+    ih.pending_var.notify_all();
+    ih.est_var.notify_all();
+    ih.rcv_var.notify_all();
+    ih.snd_var.notify_all();
+}
+
+/// Fires any expired connection timers.
+fn on_tick(ih: &InterfaceHandle) {
+    let mut cm = ih.manager.lock().unwrap();
+    let now = ih.nic.now();
+    let mut connecting = false;
+    let mut established = false;
+    let mut woken = tcp::Available::empty();
+    let ConnectionManager { connections, pending, .. } = &mut *cm;
+    for (quad, c) in connections.iter_mut() {
+        let before = c.availability();
+        let a = c.on_tick(&*ih.nic, now).unwrap_or_else(|e| {
+            // the timers will try again
+            eprintln!("failed to send on {:?}: {}", quad, e);
+            c.availability()
+        });
+        connecting |= !c.is_synchronized();
+        if a != before {
+            woken |= a;
+        }
+        if let Some(backlog) = pending.get_mut(&quad.dst.1) {
+            established |= backlog.update(quad, c);
+        }
+    }
+    // nobody is left to observe these
+    connections.retain(|_, c| !(c.detached && c.is_closed()));
+    // nor these, which died before anybody accepted them
+    for backlog in pending.values_mut() {
+        backlog.queue.retain(|quad| {
+            let dead = connections.get(quad).is_none_or(|c| c.is_closed());
+            if dead {
+                connections.remove(quad);
+            }
+            !dead
+        });
+    }
+    drop(cm);
+    if established {
+        ih.pending_var.notify_all()
+    }
+    if connecting && woken.contains(tcp::Available::READ) {
+        ih.est_var.notify_all()
+    }
+    if woken.contains(tcp::Available::READ) {
+        ih.rcv_var.notify_all()
+    }
+    if woken.contains(tcp::Available::WRITE) {
+        ih.snd_var.notify_all()
+    }
+}
+
+/// Whether both the IP header and the TCP segment checksums verify.
+fn checksums_ok(
+    iph: &etherparse::Ipv4HeaderSlice,
+    tcph: &etherparse::TcpHeaderSlice,
+    data: &[u8],
+) -> bool {
+    iph.to_header().calc_header_checksum().ok() == Some(iph.header_checksum())
+        && tcph.calc_checksum_ipv4(iph, data).ok() == Some(tcph.checksum())
+}
+
+fn on_datagram(ih: &InterfaceHandle, buf: &[u8]) -> io::Result<()> {
+    let nic = &*ih.nic;
+    let mut nbytes = buf.len();
+
+    // let eth_proto = u16::from_be_bytes([buf[2], buf[3]]);
+    // if eth_proto != 0x0800 {
+    //     // not ipv4
+    //     continue;
+    // }
+
+    match etherparse::Ipv4HeaderSlice::from_slice(&buf[..nbytes]){
+        Ok(iph) => {
+            let src = iph.source_addr();
+            let dst = iph.destination_addr();
+            if iph.protocol() != 0x06 {
+                eprintln!("BAD PROTOCOL");
+                // not tcp
+                return Ok(());
+            }
+            if (iph.total_len() as usize) < iph.slice().len() || iph.total_len() as usize > nbytes {
+                // truncated
+                return Ok(());
+            }
+            // anything past the IP datagram is link padding
+            nbytes = iph.total_len() as usize;
+            match etherparse::TcpHeaderSlice::from_slice(
+                &buf[iph.slice().len()..nbytes]) {
+                Ok(tcph) => {
+                    use std::collections::hash_map::Entry; 
+                    let datai = iph.slice().len() + tcph.slice().len();
+                    if !checksums_ok(&iph, &tcph, &buf[datai..nbytes]) {
+                        // corrupted in transit
+                        return Ok(());
+                    }
+                    let mut cmg = ih.manager.lock().unwrap();
+                    let cm = &mut *cmg;
+                    let q = tcp::Quad{
+                        src: (src, tcph.source_port()),
+                        dst: (dst, tcph.destination_port())
+                    };
+                    match cm.connections.entry(q){
+                        Entry::Occupied(mut c) 
+                        => {
+                            let connecting = !c.get().is_synchronized();
+                            let a = match c.get_mut().on_packet(
+                                nic, 
+                                iph, 
+                                tcph, 
+                                &buf[datai..nbytes],
+                                nic.now()
+                            ) {
+                                Ok(a) => a,
+                                Err(e) => {
+                                    // whatever didn't go out is left to the timers
+                                    eprintln!("failed to answer a segment on {:?}: {}", q, e);
+                                    c.get().availability()
                                 }
-                                
-                            },
-                            Entry::Vacant(e) => {
-                                if let Some(pending) = cm
+                            };
+                            let established = cm
                                 .pending
-                                .get_mut(&tcph.destination_port()) {
-                                    if let Some(c) = tcp::Connection::accept(
-                                        &mut nic, 
-                                        iph, 
-                                        tcph, 
+                                .get_mut(&q.dst.1)
+                                .is_some_and(|backlog| backlog.update(&q, c.get_mut()));
+                            // TODO: compare before/after
+                            drop(cmg);
+                            if established {
+                                ih.pending_var.notify_all()
+                            }
+                            if connecting {
+                                ih.est_var.notify_all()
+                            }
+                            if a.contains(tcp::Available::READ) {
+                                ih.rcv_var.notify_all()
+                            }
+                            if a.contains(tcp::Available::WRITE) {
+                                ih.snd_var.notify_all()
+                            }
+                            
+                        },
+                        Entry::Vacant(e) => {
+                            if let Some(backlog) = cm
+                            .pending
+                            .get_mut(&tcph.destination_port()) {
+                                let now = nic.now();
+                                let syn = tcph.syn() && !tcph.ack();
+                                let accepted = if backlog.queue.len() >= cm.config.accept_backlog {
+                                    // no room for another connection; the peer will try again
+                                    None
+                                } else if syn && backlog.half_open.len() >= cm.config.syn_backlog {
+                                    // too many half-open connections already; answer with a
+                                    // SYN cookie instead
+                                    backlog.overflowed_at = Some(now);
+                                    tcp::Connection::send_cookie(
+                                        nic,
+                                        iph,
+                                        tcph,
                                         &buf[datai..nbytes],
-                                    )? {
-                                        e.insert(c);
-                                        pending.push_back(q);
+                                        &cm.config,
+                                        &mut cm.cookies,
+                                        now,
+                                    )?;
+                                    None
+                                } else {
+                                    let cookie = if backlog.expects_cookies(now) {
+                                        tcp::Connection::accept_cookie(
+                                            nic,
+                                            iph.clone(),
+                                            tcph.clone(),
+                                            &buf[datai..nbytes],
+                                            &cm.config,
+                                            &mut cm.cookies,
+                                            now,
+                                        )?
+                                    } else {
+                                        None
+                                    };
+                                    match cookie {
+                                        Some(c) => Some(c),
+                                        None => {
+                                            let iss = iss_for(&mut *cm.iss, &q, now);
+                                            tcp::Connection::accept(
+                                                nic, 
+                                                iph, 
+                                                tcph, 
+                                                &buf[datai..nbytes],
+                                                &cm.config,
+                                                iss,
+                                                now,
+                                            )?
+                                        }
+                                    }
+                                };
+                                if let Some(c) = accepted {
+                                    let half_open = c.is_half_open();
+                                    e.insert(c);
+                                    if half_open {
+                                        backlog.half_open.insert(q);
+                                    } else {
+                                        // a returned SYN cookie completes the handshake at once
+                                        backlog.queue.push_back(q);
                                         drop(cmg);
                                         ih.pending_var.notify_all()
-                                        //TODO: wake up pending accept
                                     }
                                 }
- 
-                            } 
-                        }
-                    },
-                    Err(e) => {
-                        eprintln!("ignoring weird tcp packet {:?}", e);
+                            } else if dst == ih.addr {
+                                // nobody is listening on this port
+                                tcp::Connection::refuse(
+                                    nic,
+                                    iph,
+                                    tcph,
+                                    &buf[datai..nbytes],
+                                    nic.now(),
+                                )?;
+                            }
+                        } 
                     }
+                },
+                Err(e) => {
+                    eprintln!("ignoring weird tcp packet {:?}", e);
                 }
-
-            }
-            Err(e) => {
-                eprintln!("ignoring weird packet {:?}", e);
             }
+
+        }
+        Err(e) => {
+            eprintln!("ignoring weird packet {:?}", e);
         }
     }
+    Ok(())
 }
 
 pub struct Interface{
@@ -153,34 +468,56 @@ pub struct Interface{
 
 impl Drop for Interface {
     fn drop(&mut self) {
-        self.ih.as_mut().unwrap().manager.lock().unwrap().terminate = true;
-        drop(self.ih.take());
-        self.jh.
+        let ih = self.ih.take().unwrap();
+        ih.manager.lock().unwrap().terminate = true;
+        ih.nic.wake();
+        drop(ih);
+        let result = self.jh.
             take().
             expect("interface dropped more than once").
             join().
-            unwrap().
             unwrap();
+        if let Err(e) = result {
+            // streams and listeners have already been told
+            eprintln!("packet loop failed: {}", e);
+        }
     }
 }
 
 impl Interface {
     pub fn new() -> io::Result<Self>  {
+        Self::with_addr(DEFAULT_ADDR)
+    }
+
+    /// Opens tun0 and uses `addr` as our side's address for outgoing connections.
+    pub fn with_addr(addr: Ipv4Addr) -> io::Result<Self>  {
         let nic = tun_tap::Iface::without_packet_info(
             "tun0", 
             tun_tap::Mode::Tun)?;
-        let ih: InterfaceHandle = Arc::default();
+        Ok(Self::with_device(nic, addr))
+    }
+
+    /// Runs the stack over `device` instead of tun0, using `addr` as our side's address.
+    pub fn with_device<D: PacketDevice + 'static>(device: D, addr: Ipv4Addr) -> Self {
+        let ih: InterfaceHandle = Arc::new(Foobar {
+            nic: Box::new(device::FixedMtu::new(device)),
+            addr,
+            manager: Mutex::default(),
+            pending_var: Condvar::new(),
+            rcv_var: Condvar::new(),
+            snd_var: Condvar::new(),
+            est_var: Condvar::new(),
+        });
         let jh = {
             let ih = ih.clone();
             thread::spawn(move || {
-            packet_loop(nic, ih)
+            packet_loop(ih)
         })};
          
-        Ok (Interface {
+        Interface {
             ih: Some(ih),
             jh: Some(jh),
-        })
-        
+        }
     }
 
     pub fn bind(&mut self, port: u16) -> io::Result<TcpListener> {
@@ -188,7 +525,7 @@ impl Interface {
         let mut cm = self.ih.as_mut().unwrap().manager.lock().unwrap();
         match cm.pending.entry(port) {
             Entry::Vacant(v) => {
-                v.insert(VecDeque::new());
+                v.insert(Backlog::default());
             },
             Entry::Occupied(_) => {
                 return Err(io::Error::new(
@@ -205,6 +542,48 @@ impl Interface {
                 h: self.ih.as_mut().unwrap().clone()
             })
     }
+
+    /// Replaces the settings used for connections opened or accepted from now on.
+    pub fn set_config(&mut self, config: Config) {
+        self.ih.as_mut().unwrap().manager.lock().unwrap().config = config;
+    }
+
+    /// Replaces the generator of initial sequence numbers for connections opened or
+    /// accepted from now on. Interfaces start out with a [`KeyedIss`] of their own.
+    pub fn set_iss_generator<G: IssGenerator + 'static>(&mut self, generator: G) {
+        self.ih.as_mut().unwrap().manager.lock().unwrap().iss = Box::new(generator);
+    }
+
+    /// Opens a connection to `addr:port`, blocking until the three-way handshake completes.
+    pub fn connect(&mut self, addr: Ipv4Addr, port: u16) -> io::Result<TcpStream> {
+        let ih = self.ih.as_ref().unwrap().clone();
+        let mut cm = ih.manager.lock().unwrap();
+        if let Some(kind) = cm.failed {
+            return Err(io::Error::new(kind, "the packet device failed"));
+        }
+        let local = cm.ephemeral_port(ih.addr, (addr, port))?;
+        let quad = Quad {
+            src: (addr, port),
+            dst: (ih.addr, local)
+        };
+        let now = ih.nic.now();
+        let iss = iss_for(&mut *cm.iss, &quad, now);
+        let c = tcp::Connection::connect(&*ih.nic, quad, &cm.config, iss, now)?;
+        cm.connections.insert(quad, c);
+        ih.nic.wake();
+        loop {
+            let c = cm.connection(&quad)?;
+            if let Some(kind) = c.error {
+                cm.connections.remove(&quad);
+                return Err(io::Error::new(kind, "connection could not be established"));
+            }
+            if c.is_synchronized() {
+                drop(cm);
+                return Ok(TcpStream { quad, h: ih });
+            }
+            cm = ih.est_var.wait(cm).unwrap();
+        }
+    }
 }
 
 pub struct TcpListener{
@@ -219,9 +598,13 @@ impl Drop for TcpListener {
         pending.
         remove(&self.port).
         expect("port closed while listener still active");
-        for quad in pending {
-            //TODO: terminate cm.connections[quad]
-            unimplemented!()
+        for quad in pending.queue.into_iter().chain(pending.half_open) {
+            // nobody will ever accept these, so don't leave the peer hanging
+            if let Some(mut c) = cm.connections.remove(&quad) {
+                if let Err(e) = c.abort(&*self.h.nic) {
+                    eprintln!("failed to reset {:?}: {}", quad, e);
+                }
+            }
         }
     }
 }
@@ -229,51 +612,50 @@ impl Drop for TcpListener {
 impl Write for TcpStream {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize>{   
         let mut cm = self.h.manager.lock().unwrap();
-        let c = cm.connections.
-                                get_mut(&self.quad).
-                                ok_or_else(|| 
-            io::Error::new(
-                io::ErrorKind::ConnectionAborted,
-                "stream was terminated unexpectedly")
-            )?;
-        if c.unacked.len() >= SENDQUEUE_SIZE {
-            // TODO: block
-            return 
-            Err(io::
-                Error::new
-                (io::ErrorKind::WouldBlock, "too many bytes buffered"
-            ));
-        }
+        loop {
+            let c = cm.connection(&self.quad)?;
 
-        let nwrite = std::cmp::min(
-                            buf.len(), 
-                            SENDQUEUE_SIZE - c.unacked.len());
-        c.unacked.extend(buf[..nwrite].iter());
+            if let Some(kind) = c.error {
+                return Err(kind.into());
+            }
 
-        // TODO: wake up a writer
+            if c.is_snd_closed() {
+                return Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "stream is closed for writing",
+                ));
+            }
+
+            if c.send_space() > 0 {
+                let nwrite = std::cmp::min(buf.len(), c.send_space());
+                c.unacked.extend(buf[..nwrite].iter());
+                // the data is ours to deliver now; if the device fails to send it, the
+                // retransmission timer tries again
+                let _ = c.transmit(&*self.h.nic, self.h.nic.now());
+                self.h.nic.wake();
+                return Ok(nwrite);
+            }
 
-        Ok(nwrite)
+            cm = self.h.snd_var.wait(cm).unwrap();
+        }
     }
+
     fn flush(&mut self) -> io::Result<()>{ 
         let mut cm = self.h.manager.lock().unwrap();
-        let c = cm.connections.
-            get_mut(&self.quad).ok_or_else(|| 
-            io::Error::new(
-                io::ErrorKind::ConnectionAborted, 
-                "stream was terminated unexpectedly")
-            )?;
-        if c.unacked.is_empty() {
-            // TODO: block
-            Ok(())
-        }
-        else {
-            Err(io::
-                Error::new
-                (io::ErrorKind::WouldBlock, 
-                "too many bytes buffered"
-            ))
-        }
+        loop {
+            let c = cm.connection(&self.quad)?;
+
+            if let Some(kind) = c.error {
+                return Err(kind.into());
+            }
 
+            if c.unacked.is_empty() {
+                // everything we buffered has been acknowledged
+                return Ok(());
+            }
+
+            cm = self.h.snd_var.wait(cm).unwrap();
+        }
      }
 
 }
@@ -282,12 +664,11 @@ impl Read for TcpStream {
     fn read(&mut self, buf: &mut[u8]) -> io::Result<usize>{ 
         let mut cm = self.h.manager.lock().unwrap();
         loop {
-            let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
-                io::Error::new(
-                    io::ErrorKind::ConnectionAborted,
-                    "stream was terminated unexpectedly",
-                )
-            })?;
+            let c = cm.connection(&self.quad)?;
+
+            if let Some(kind) = c.error {
+                return Err(kind.into());
+            }
 
             if c.is_rcv_closed() && c.incoming.is_empty() {
                 // no more data to read, and no need to block, because there won't be any more
@@ -298,12 +679,16 @@ impl Read for TcpStream {
                 let mut nread = 0;
                 let (head, tail) = c.incoming.as_slices();
                 let hread = std::cmp::min(buf.len(), head.len());
-                buf.copy_from_slice(&head[..hread]);
+                buf[..hread].copy_from_slice(&head[..hread]);
                 nread += hread;
                 let tread = std::cmp::min(buf.len() - nread, tail.len());
-                buf.copy_from_slice(&tail[..tread]);
+                buf[nread..nread + tread].copy_from_slice(&tail[..tread]);
                 nread += tread;
                 drop(c.incoming.drain(..nread));
+                // the data is the caller's now; a window update that fails to go out is
+                // made up for by the peer's window probes
+                let _ = c.on_read(&*self.h.nic, self.h.nic.now());
+                self.h.nic.wake();
                 return Ok(nread);
             }
 
@@ -323,12 +708,16 @@ impl TcpListener {
             .pending
             .get_mut(&self.port)
             .expect("port closed while listener still active")
+            .queue
             .pop_front() {
                 return Ok(TcpStream{
-                    quad: quad, 
+                    quad, 
                     h: self.h.clone()
                 });
             }
+            if let Some(kind) = cm.failed {
+                return Err(io::Error::new(kind, "the packet device failed"));
+            }
             cm = self.h.pending_var.wait(cm).unwrap();
 
         }
@@ -341,15 +730,98 @@ impl Drop for TcpStream {
         let mut cm = self.h.manager.lock().unwrap();
         if let Some(c) = cm.
         connections.
-        remove(&self.quad){
-            //TODO: send FIN on cm.connections[quad]
-            unimplemented!()
+        get_mut(&self.quad){
+            // the packet loop carries the FIN handshake through from here
+            let now = self.h.nic.now();
+            c.detach(now);
+            c.shutdown_read();
+            if let Err(e) = c.close(&*self.h.nic, now) {
+                eprintln!("failed to close {:?}: {}", self.quad, e);
+            }
+            self.h.nic.wake();
+            if c.is_closed() {
+                cm.connections.remove(&self.quad);
+            }
         }
     }
 }
 
 impl TcpStream {
+    /// Runs `f` on the connection behind this stream, failing with `ConnectionAborted` if it
+    /// has gone away.
+    fn with_connection<T>(
+        &self,
+        f: impl FnOnce(&mut tcp::Connection) -> io::Result<T>,
+    ) -> io::Result<T> {
+        let mut cm = self.h.manager.lock().unwrap();
+        let result = f(cm.connection(&self.quad)?);
+        // in case f armed a timer
+        self.h.nic.wake();
+        result
+    }
+
+    /// Shuts down the read half, the write half, or both halves of this connection,
+    /// like `std::net::TcpStream::shutdown`.
+    ///
+    /// Shutting down writes sends a FIN once everything already written has gone out; further
+    /// writes fail with `BrokenPipe`. Shutting down reads discards anything unread or still
+    /// to arrive, and makes `read` return 0.
     pub fn shutdown (&self, how: std::net::Shutdown) -> io::Result<()> {
-        unimplemented!()
+        self.with_connection(|c| {
+            if let std::net::Shutdown::Read | std::net::Shutdown::Both = how {
+                c.shutdown_read();
+            }
+            if let std::net::Shutdown::Write | std::net::Shutdown::Both = how {
+                c.close(&*self.h.nic, self.h.nic.now())?;
+            }
+            Ok(())
+        })?;
+        self.h.rcv_var.notify_all();
+        self.h.snd_var.notify_all();
+        Ok(())
+    }
+    /// Disables Nagle's algorithm when `nodelay` is true, like `TCP_NODELAY`, so that small
+    /// writes go out right away instead of being coalesced while earlier data is
+    /// unacknowledged. Nagle's algorithm is on for new connections.
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.with_connection(|c| c.set_nodelay(&*self.h.nic, nodelay, self.h.nic.now()))
     }
-}
\ No newline at end of file
+
+    /// Whether Nagle's algorithm is disabled; see [`TcpStream::set_nodelay`].
+    pub fn nodelay(&self) -> io::Result<bool> {
+        self.with_connection(|c| Ok(c.nodelay()))
+    }
+
+    /// Probes the peer after `idle` time without hearing from it, like `SO_KEEPALIVE` with
+    /// `TCP_KEEPIDLE`, or stops probing if `None`. Probes are repeated every
+    /// [`Config::keepalive_interval`], and once [`Config::keepalive_probes`] of them have gone
+    /// unanswered, reads and writes fail with `TimedOut`. Keepalive is off for new connections.
+    ///
+    /// An `idle` time of zero is rejected with `InvalidInput`.
+    pub fn set_keepalive(&self, idle: Option<Duration>) -> io::Result<()> {
+        if idle == Some(Duration::ZERO) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot set a zero keepalive time",
+            ));
+        }
+        self.with_connection(|c| {
+            c.set_keepalive(idle, self.h.nic.now());
+            Ok(())
+        })
+    }
+
+    /// The idle time after which the peer is probed; see [`TcpStream::set_keepalive`].
+    pub fn keepalive(&self) -> io::Result<Option<Duration>> {
+        self.with_connection(|c| Ok(c.keepalive()))
+    }
+
+    /// Selects the congestion control algorithm for this connection, like `TCP_CONGESTION`.
+    /// Connections start out with [`NewReno`].
+    pub fn set_congestion_control<C: CongestionControl + 'static>(&self, algorithm: C) -> io::Result<()> {
+        self.with_connection(|c| {
+            c.set_congestion_control(Box::new(algorithm));
+            Ok(())
+        })
+    }
+}
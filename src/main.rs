@@ -1,8 +1,5 @@
-extern crate tun_tap;
 use std::{io::{self, Read}, thread};
 
-mod tcp;
-
 fn main() -> io::Result<()> {
     let mut i = trust::Interface::new()?;
     let mut l1 = i.bind(8000)?;
@@ -0,0 +1,401 @@
+//! An in-process network for running stacks against each other without a TUN device.
+//!
+//! A [`Network`] hands out [`SimDevice`]s, one per IPv4 address, and routes packets between
+//! them by destination address. Every packet is subject to the loss, duplication,
+//! corruption, reordering and latency configured in [`LinkConfig`], decided by a random
+//! number generator seeded per direction of traffic, so a given seed impairs the same
+//! packets of a flow on every run.
+//!
+//! Time is virtual: [`SimDevice::now`](crate::PacketDevice::now) reports the network's
+//! clock, which only moves forward once every device is blocked in `recv`, and then jumps
+//! straight to the next packet delivery or receive timeout. Retransmission timers and the
+//! like therefore fire after the same amount of simulated time no matter how fast the host
+//! is, and a test that waits out a minute of TIME-WAIT finishes in milliseconds. Devices can
+//! be woken, so an `Interface` only waits for the packets and timers it expects, and a long
+//! idle stretch costs a single jump of the clock.
+//!
+//! The network can't see threads that don't hold a device, though. Once every device is
+//! blocked, it gives them a millisecond of real time to act before moving the clock on, so
+//! whether an application thread gets to act before a timer fires depends on the host's
+//! scheduling when that thread is slower than that. A thread that needs to act at an exact
+//! virtual time should hold a device of its own, as a scripted peer does: the clock stands
+//! still until it blocks in `recv`.
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::PacketDevice;
+
+// how long every device must sit idle in real time before the virtual clock is moved on,
+// so that application threads get to act at the virtual time they were woken at; see the
+// module documentation for what this can't guarantee
+const QUIET_PERIOD: Duration = Duration::from_millis(1);
+
+/// Impairments applied to every packet sent over a [`Network`].
+#[derive(Clone, Debug)]
+pub struct LinkConfig {
+    /// Probability that a packet is dropped.
+    pub loss: f64,
+    /// Probability that a packet is delivered twice.
+    pub duplicate: f64,
+    /// Probability that a bit of a packet is flipped in transit.
+    pub corrupt: f64,
+    /// Probability that a packet is held back by an extra `reorder_delay`, letting packets
+    /// sent after it overtake it.
+    pub reorder: f64,
+    /// Extra delay of reordered packets.
+    pub reorder_delay: Duration,
+    /// One-way delay of every packet.
+    pub latency: Duration,
+    /// Largest packet the link carries; devices refuse to send anything bigger.
+    pub mtu: usize,
+}
+
+impl Default for LinkConfig {
+    /// A perfect link with 5ms of latency in each direction and an Ethernet-sized MTU.
+    fn default() -> Self {
+        LinkConfig {
+            loss: 0.0,
+            duplicate: 0.0,
+            corrupt: 0.0,
+            reorder: 0.0,
+            reorder_delay: Duration::from_millis(10),
+            latency: Duration::from_millis(5),
+            mtu: 1500,
+        }
+    }
+}
+
+/// A small, seedable pseudo-random number generator (SplitMix64).
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns true with probability `p`.
+    fn chance(&mut self, p: f64) -> bool {
+        p > 0.0 && ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+struct InFlight {
+    deliver_at: Instant,
+    // breaks ties between packets due at the same time in the order they were sent
+    id: u64,
+    to: Ipv4Addr,
+    packet: Vec<u8>,
+}
+
+impl PartialEq for InFlight {
+    fn eq(&self, other: &Self) -> bool {
+        (self.deliver_at, self.id) == (other.deliver_at, other.id)
+    }
+}
+
+impl Eq for InFlight {}
+
+impl PartialOrd for InFlight {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for InFlight {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.deliver_at, self.id).cmp(&(other.deliver_at, other.id))
+    }
+}
+
+#[derive(Default)]
+struct Endpoint {
+    // set while the device is blocked in recv, to the time it will give up (None: never)
+    waiting: Option<Option<Instant>>,
+    // set by wake until recv returns
+    woken: bool,
+}
+
+struct NetState {
+    now: Instant,
+    seed: u64,
+    config: LinkConfig,
+    rngs: HashMap<(Ipv4Addr, Ipv4Addr), Rng>,
+    in_flight: BinaryHeap<Reverse<InFlight>>,
+    endpoints: HashMap<Ipv4Addr, Endpoint>,
+    next_id: u64,
+    // bumped on every send, so idle devices can tell whether anything happened
+    generation: u64,
+}
+
+impl NetState {
+    fn rng(&mut self, from: Ipv4Addr, to: Ipv4Addr) -> &mut Rng {
+        let seed = self.seed;
+        self.rngs.entry((from, to)).or_insert_with(|| {
+            let mut rng = Rng(seed ^ (u64::from(u32::from(from)) << 32 | u64::from(u32::from(to))));
+            rng.next_u64();
+            rng
+        })
+    }
+
+    fn enqueue(&mut self, to: Ipv4Addr, packet: Vec<u8>, delay: Duration) {
+        self.next_id += 1;
+        self.in_flight.push(Reverse(InFlight {
+            deliver_at: self.now + delay,
+            id: self.next_id,
+            to,
+            packet,
+        }));
+    }
+
+    /// Takes the earliest packet for `addr` that is due by now.
+    fn take_due(&mut self, addr: Ipv4Addr) -> Option<Vec<u8>> {
+        let mut held = Vec::new();
+        let mut found = None;
+        while let Some(Reverse(p)) = self.in_flight.pop() {
+            if p.deliver_at > self.now {
+                self.in_flight.push(Reverse(p));
+                break;
+            }
+            if p.to == addr {
+                found = Some(p.packet);
+                break;
+            }
+            held.push(Reverse(p));
+        }
+        self.in_flight.extend(held);
+        found
+    }
+
+    /// When something next happens if nobody sends anything in the meantime. Packets that
+    /// are already due are just waiting for their receiver to pick them up.
+    fn next_event(&self) -> Option<Instant> {
+        let deliveries = self.in_flight.iter().map(|Reverse(p)| p.deliver_at);
+        let timeouts = self.endpoints.values().filter_map(|e| e.waiting.flatten());
+        deliveries.chain(timeouts).filter(|&t| t > self.now).min()
+    }
+
+    /// Whether every device is blocked in `recv` with nothing but the passing of time to wake
+    /// it up. A device that has a packet due or has reached its deadline may just not have
+    /// been scheduled yet to return from `recv`.
+    fn all_waiting(&self) -> bool {
+        self.endpoints.iter().all(|(addr, e)| match e.waiting {
+            Some(deadline) => {
+                !e.woken
+                    && deadline.is_none_or(|deadline| deadline > self.now)
+                    && !self
+                        .in_flight
+                        .iter()
+                        .any(|Reverse(p)| p.to == *addr && p.deliver_at <= self.now)
+            }
+            None => false,
+        })
+    }
+}
+
+struct Shared {
+    state: Mutex<NetState>,
+    cv: Condvar,
+}
+
+/// A simulated network connecting any number of [`SimDevice`]s.
+#[derive(Clone)]
+pub struct Network {
+    shared: Arc<Shared>,
+}
+
+impl Network {
+    /// Creates a network whose impairments are drawn from a generator seeded with `seed`.
+    pub fn new(seed: u64, config: LinkConfig) -> Self {
+        Network {
+            shared: Arc::new(Shared {
+                state: Mutex::new(NetState {
+                    now: Instant::now(),
+                    seed,
+                    config,
+                    rngs: HashMap::new(),
+                    in_flight: BinaryHeap::new(),
+                    endpoints: HashMap::new(),
+                    next_id: 0,
+                    generation: 0,
+                }),
+                cv: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Attaches a device with address `addr`, which receives every packet sent to it.
+    ///
+    /// # Panics
+    ///
+    /// If a device with that address is already attached.
+    pub fn device(&self, addr: Ipv4Addr) -> SimDevice {
+        let mut state = self.shared.state.lock().unwrap();
+        assert!(
+            !state.endpoints.contains_key(&addr),
+            "{} is already attached to the network",
+            addr
+        );
+        state.endpoints.insert(addr, Endpoint::default());
+        SimDevice {
+            addr,
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// Changes the impairments applied to packets sent from now on.
+    pub fn set_config(&self, config: LinkConfig) {
+        self.shared.state.lock().unwrap().config = config;
+    }
+
+    /// The current virtual time.
+    pub fn now(&self) -> Instant {
+        self.shared.state.lock().unwrap().now
+    }
+}
+
+/// One attachment point on a [`Network`]; hand it to `Interface::with_device`, or drive it
+/// by hand to script a peer.
+///
+/// Virtual time stands still while an attached device is not blocked in `recv`. A thread
+/// scripting a peer should therefore only block on the stacks under test while nothing it
+/// sent is still in flight (a zero-latency link helps), and drop the device before dropping
+/// the interfaces, which need time to pass to shut down.
+pub struct SimDevice {
+    addr: Ipv4Addr,
+    shared: Arc<Shared>,
+}
+
+impl SimDevice {
+    pub fn addr(&self) -> Ipv4Addr {
+        self.addr
+    }
+}
+
+impl Drop for SimDevice {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.endpoints.remove(&self.addr);
+        drop(state);
+        // the remaining devices may now all be idle
+        self.shared.cv.notify_all();
+    }
+}
+
+impl PacketDevice for SimDevice {
+    fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        let to = match etherparse::Ipv4HeaderSlice::from_slice(packet) {
+            Ok(iph) => iph.destination_addr(),
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{:?}", e))),
+        };
+
+        let mut state = self.shared.state.lock().unwrap();
+        if packet.len() > state.config.mtu {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "packet is larger than the link's MTU",
+            ));
+        }
+        state.generation += 1;
+        if state.endpoints.contains_key(&to) {
+            let config = state.config.clone();
+            let rng = state.rng(self.addr, to);
+            let lost = rng.chance(config.loss);
+            let copies = if rng.chance(config.duplicate) { 2 } else { 1 };
+            let mut deliveries = Vec::new();
+            for _ in 0..copies {
+                let mut packet = packet.to_vec();
+                if rng.chance(config.corrupt) {
+                    let bit = rng.below(packet.len() * 8);
+                    packet[bit / 8] ^= 1 << (bit % 8);
+                }
+                let mut delay = config.latency;
+                if rng.chance(config.reorder) {
+                    delay += config.reorder_delay;
+                }
+                deliveries.push((packet, delay));
+            }
+            if !lost {
+                for (packet, delay) in deliveries {
+                    state.enqueue(to, packet, delay);
+                }
+            }
+        }
+        drop(state);
+        self.shared.cv.notify_all();
+        Ok(packet.len())
+    }
+
+    fn recv(&self, buf: &mut [u8], timeout: Option<Duration>) -> io::Result<usize> {
+        let mut state = self.shared.state.lock().unwrap();
+        let deadline = timeout.map(|timeout| state.now + timeout);
+        loop {
+            if let Some(packet) = state.take_due(self.addr) {
+                let n = std::cmp::min(buf.len(), packet.len());
+                buf[..n].copy_from_slice(&packet[..n]);
+                return Ok(n);
+            }
+            let woken = state
+                .endpoints
+                .get_mut(&self.addr)
+                .is_some_and(|e| std::mem::take(&mut e.woken));
+            if woken || deadline.is_some_and(|deadline| deadline <= state.now) {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+
+            if let Some(e) = state.endpoints.get_mut(&self.addr) {
+                e.waiting = Some(deadline);
+            }
+            let (generation, now) = (state.generation, state.now);
+            if state.all_waiting() {
+                // give application threads a moment to act before time moves on
+                state = self.shared.cv.wait_timeout(state, QUIET_PERIOD).unwrap().0;
+                // unless another device moved it on meanwhile, maybe past our own deadline
+                if state.generation == generation && state.now == now && state.all_waiting() {
+                    if let Some(next) = state.next_event() {
+                        state.now = next;
+                    }
+                    self.shared.cv.notify_all();
+                }
+            } else {
+                state = self.shared.cv.wait(state).unwrap();
+            }
+            if let Some(e) = state.endpoints.get_mut(&self.addr) {
+                e.waiting = None;
+            }
+        }
+    }
+
+    fn now(&self) -> Instant {
+        self.shared.state.lock().unwrap().now
+    }
+
+    fn mtu(&self) -> usize {
+        self.shared.state.lock().unwrap().config.mtu
+    }
+
+    fn can_wake(&self) -> bool {
+        true
+    }
+
+    fn wake(&self) {
+        let mut state = self.shared.state.lock().unwrap();
+        if let Some(e) = state.endpoints.get_mut(&self.addr) {
+            e.woken = true;
+        }
+        drop(state);
+        self.shared.cv.notify_all();
+    }
+}
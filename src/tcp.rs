@@ -1,9 +1,74 @@
-use std::io::prelude::*;
 use std::cmp::{Ord, Ordering};
 use std::net::Ipv4Addr;
-use std::{io, usize};
+use std::io;
 use std::io::Write;
 use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use congestion::Congestion;
+use options::Options;
+use reassembly::Reassembly;
+use scoreboard::Scoreboard;
+
+use crate::PacketDevice;
+
+pub use congestion::{CongestionControl, CongestionWindow, Cubic, NewReno};
+pub use iss::{IssGenerator, KeyedIss};
+pub(crate) use cookie::SynCookies;
+
+mod congestion;
+mod cookie;
+mod iss;
+mod options;
+mod reassembly;
+mod scoreboard;
+mod siphash;
+
+// RFC 6298 (2.1): RTO before any round-trip time has been measured
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+// RFC 6298 (2.4): lower bound on the RTO
+const MIN_RTO: Duration = Duration::from_secs(1);
+// RFC 6298 (2.5): upper bound on the RTO once backed off
+const MAX_RTO: Duration = Duration::from_secs(60);
+// RFC 6298 (2.4): clock granularity G used in the RTO computation
+const CLOCK_GRANULARITY: Duration = Duration::from_millis(1);
+// retransmissions of the same segment before the connection is given up on
+const MAX_RETRANSMITS: u32 = 8;
+// the peer's MSS when its SYN doesn't say (RFC 9293, 3.7.1)
+const DEFAULT_MSS: u16 = 536;
+// the smallest MSS we take the peer at its word for; like Linux's TCP_MIN_MSS, it leaves
+// room for data next to a full 40 bytes of options
+const MIN_MSS: u16 = 88;
+// IP and TCP headers without options, which the MSS leaves room for
+const HEADERS_LEN: usize = 40;
+// largest window scale shift either side may use (RFC 7323, 2.3)
+const MAX_WSCALE: u8 = 14;
+// how long TS.Recent stays valid without hearing from the peer (RFC 7323, 5.5)
+const PAWS_IDLE: Duration = Duration::from_secs(24 * 24 * 60 * 60);
+// maximum segment lifetime; TIME-WAIT lasts twice this
+const DEFAULT_MSL: Duration = Duration::from_secs(30);
+// how long a connection nobody refers to anymore waits in FIN-WAIT-2 for the peer's FIN,
+// like Linux's tcp_fin_timeout
+const DEFAULT_FIN_TIMEOUT: Duration = Duration::from_secs(60);
+// half-open connections a listener holds before it answers SYNs with cookies
+const DEFAULT_SYN_BACKLOG: usize = 128;
+// connections a listener holds until they are accepted, like Linux's somaxconn
+const DEFAULT_ACCEPT_BACKLOG: usize = 4096;
+// how long an ACK may be held back waiting for more data or something to send
+const DEFAULT_ACK_DELAY: Duration = Duration::from_millis(40);
+// RFC 1122 (4.2.3.2): an ACK must never be delayed longer than this
+const MAX_ACK_DELAY: Duration = Duration::from_millis(500);
+// room for received data the application hasn't read yet, which bounds our receive window
+const DEFAULT_RECV_BUFFER: usize = 64 * 1024;
+// room for written data the peer hasn't acknowledged yet, which bounds what we keep in flight
+const DEFAULT_SEND_BUFFER: usize = 64 * 1024;
+// RFC 1122 (4.2.3.6): time between keepalive probes, and how many go unanswered before
+// the peer is given up on
+const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(75);
+const DEFAULT_KEEPALIVE_PROBES: u32 = 9;
+// segments acknowledged right away after the handshake or out-of-order data, so the
+// peer's slow start or loss recovery isn't held up by our delayed ACKs
+const QUICK_ACKS: u32 = 8;
 
 bitflags::bitflags! {
     pub struct Available: u8 {
@@ -12,13 +77,71 @@ bitflags::bitflags! {
     }
 }
 
-use etherparse::TcpHeaderSlice;
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum State {
+    Listen,
+    SynSent,
     SynRcvd,
     Estab,
     FinWait1,
     FinWait2,
-    TimeWait
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+    Closed
+}
+
+/// Settings shared by every connection on an `Interface`.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Maximum segment lifetime. Connections linger in TIME-WAIT for twice this long.
+    pub msl: Duration,
+    /// How long a connection whose stream has been dropped waits in FIN-WAIT-2 for the
+    /// peer's FIN before it is given up on, since no application is left to notice a peer
+    /// that never closes its side.
+    pub fin_timeout: Duration,
+    /// Half-open connections a listener may hold. Beyond this, SYNs are answered with SYN
+    /// cookies, and connections only come to be once the peer's ACK returns one; these
+    /// connections do without window scaling, timestamps and SACK. ACKs are only checked for
+    /// cookies for two minutes after a SYN was last answered with one.
+    pub syn_backlog: usize,
+    /// Established connections a listener may hold until they are accepted, counting ones
+    /// that closed before being accepted. Beyond this, segments that would open another
+    /// connection are dropped, and the peer is left to retry.
+    pub accept_backlog: usize,
+    /// How long an ACK for in-order data may wait to be combined with the next segment we
+    /// send or with the ACK for the next segment received. Capped at 500ms; zero turns
+    /// delayed ACKs off.
+    pub ack_delay: Duration,
+    /// Time between keepalive probes on connections with keepalive enabled, once the first
+    /// one has gone unanswered.
+    pub keepalive_interval: Duration,
+    /// Unanswered keepalive probes after which the connection is dropped with `TimedOut`.
+    pub keepalive_probes: u32,
+    /// Bytes of received data held for the application to read. The window we advertise is
+    /// the free part of this buffer, and the window scale we offer is sized for all of it.
+    pub recv_buffer: usize,
+    /// Bytes of written data held until the peer acknowledges them, both in flight and not
+    /// yet sent. Writes block while this buffer is full, so it caps how much a connection can
+    /// have in flight per round trip.
+    pub send_buffer: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            msl: DEFAULT_MSL,
+            fin_timeout: DEFAULT_FIN_TIMEOUT,
+            syn_backlog: DEFAULT_SYN_BACKLOG,
+            accept_backlog: DEFAULT_ACCEPT_BACKLOG,
+            ack_delay: DEFAULT_ACK_DELAY,
+            keepalive_interval: DEFAULT_KEEPALIVE_INTERVAL,
+            keepalive_probes: DEFAULT_KEEPALIVE_PROBES,
+            recv_buffer: DEFAULT_RECV_BUFFER,
+            send_buffer: DEFAULT_SEND_BUFFER,
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
@@ -30,11 +153,17 @@ pub struct Quad {
 impl State {
     fn is_synchronized(&self) -> bool {
         match *self {
+            State:: Listen => false,
+            State:: SynSent => false,
             State:: SynRcvd => false,
             State:: Estab => true,
             State:: FinWait1 => true,
             State:: FinWait2 => true,
-            State:: TimeWait => true
+            State:: CloseWait => true,
+            State:: Closing => true,
+            State:: LastAck => true,
+            State:: TimeWait => true,
+            State:: Closed => false
         }
     }
     
@@ -46,25 +175,215 @@ pub struct Connection {
     recv: RecvSequenceSpace,
     ip: etherparse::Ipv4Header,
     tcp: etherparse::TcpHeader,
+    config: Config,
+    timers: Timers,
+    cc: Congestion,
+    // the MSS we advertise, from the MTU of our link
+    local_mss: u16,
+    // the most data we put in a segment: the smaller of our and the peer's MSS
+    mss: usize,
+    // the window scale shift our SYN carries, unless the peer's SYN came without one
+    wscale_offer: Option<u8>,
+    // timestamp option state, unless the peer's SYN came without one
+    timestamps: Option<Timestamps>,
+    // SACK is offered in our SYN, and stays on if the peer's SYN permits it too
+    sack: bool,
+    // the ranges the peer has selectively acknowledged
+    scoreboard: Scoreboard,
+    // Nagle's algorithm is off, so small segments go out even while data is unacknowledged
+    nodelay: bool,
+    // in-order bytes received since we last sent an ACK
+    rcv_unacked: usize,
+    // segments still to be acknowledged without delay
+    quick_acks: u32,
+    // idle time after which the peer is probed, if keepalive is on
+    keepalive: Option<Duration>,
+    // the application is done writing; a FIN follows the remaining data
+    closed: bool,
+    // sequence number of our FIN, once we have decided to send one
+    closed_at: Option<u32>,
+    // the application is done reading; further data is acknowledged and dropped
+    rd_closed: bool,
+    // data that arrived ahead of recv.nxt
+    out_of_order: Reassembly,
 
     pub(crate) incoming: VecDeque<u8>,
-    pub(crate) unacked: VecDeque<u8>
+    pub(crate) unacked: VecDeque<u8>,
+    // set when the connection was torn down abnormally, e.g. refused by the peer
+    pub(crate) error: Option<io::ErrorKind>,
+    // no TcpStream refers to this connection anymore, so it can go once it is closed
+    pub(crate) detached: bool
 }
 
 impl Connection {
     pub(crate) fn is_rcv_closed(&self) -> bool {
-        if let State::TimeWait = self.state {
+        // the peer has sent its FIN, or the connection is gone altogether
+        self.rd_closed || matches!(
+            self.state,
+            State::CloseWait | State::Closing | State::LastAck | State::TimeWait | State::Closed
+        )
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        self.state == State::Closed
+    }
+
+    pub(crate) fn is_snd_closed(&self) -> bool {
+        self.closed || self.state == State::Closed
+    }
+
+    /// Stops accepting data from the application: a FIN is queued behind whatever is still
+    /// buffered in `unacked` (RFC 793, "CLOSE Call").
+    pub(crate) fn close(&mut self, nic: &dyn PacketDevice, now: Instant) -> io::Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        self.closed = true;
+        match self.state {
+            State::Listen | State::SynSent => self.state = State::Closed,
+            State::Estab | State::CloseWait => {
+                self.queue_fin();
+                self.transmit(nic, now)?;
+            }
+            // the FIN is queued once we reach ESTABLISHED
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Tears the connection down with a reset instead of a FIN (RFC 793, "ABORT Call").
+    pub(crate) fn abort(&mut self, nic: &dyn PacketDevice) -> io::Result<()> {
+        let r = match self.state {
+            State::SynRcvd
+            | State::Estab
+            | State::FinWait1
+            | State::FinWait2
+            | State::CloseWait => {
+                // <SEQ=SND.NXT><CTL=RST>
+                let ack = self.tcp.ack;
+                self.tcp.sequence_number = self.send.nxt;
+                self.tcp.ack = false;
+                self.tcp.rst = true;
+                let r = self.send_raw(nic, &[]);
+                self.tcp.rst = false;
+                self.tcp.ack = ack;
+                r.map(|_| ())
+            }
+            _ => Ok(()),
+        };
+        self.state = State::Closed;
+        self.error = Some(io::ErrorKind::ConnectionAborted);
+        r
+    }
+
+    /// Switches to another congestion control algorithm, keeping the current window.
+    pub(crate) fn set_congestion_control(&mut self, algorithm: Box<dyn CongestionControl>) {
+        self.cc.set_algorithm(algorithm);
+    }
+
+    pub(crate) fn nodelay(&self) -> bool {
+        self.nodelay
+    }
+
+    /// Turns Nagle's algorithm off or back on, sending whatever it was holding back.
+    pub(crate) fn set_nodelay(
+        &mut self,
+        nic: &dyn PacketDevice,
+        nodelay: bool,
+        now: Instant,
+    ) -> io::Result<()> {
+        self.nodelay = nodelay;
+        self.transmit(nic, now)?;
+        Ok(())
+    }
+
+    pub(crate) fn keepalive(&self) -> Option<Duration> {
+        self.keepalive
+    }
+
+    /// Starts probing the peer after `idle` time without hearing from it, or stops if `None`.
+    pub(crate) fn set_keepalive(&mut self, idle: Option<Duration>, now: Instant) {
+        self.keepalive = idle;
+        self.on_peer_alive(now);
+    }
+
+    /// Restarts the keepalive timer, as the peer has shown signs of life.
+    fn on_peer_alive(&mut self, now: Instant) {
+        self.timers.keepalive = self.keepalive.map(|idle| now + idle);
+        self.timers.keepalive_probes = 0;
+        self.timers.persist_probes = 0;
+    }
+
+    /// Stops delivering data to the application and throws away whatever is unread.
+    pub(crate) fn shutdown_read(&mut self) {
+        self.rd_closed = true;
+        self.incoming.clear();
+        // the next segment we send tells the peer
+        self.open_window();
+    }
+
+    /// Lets the peer know about buffer space the application freed by reading, once there is
+    /// enough of it to be worth a window update.
+    pub(crate) fn on_read(&mut self, nic: &dyn PacketDevice, now: Instant) -> io::Result<()> {
+        let receiving = matches!(self.state, State::Estab | State::FinWait1 | State::FinWait2);
+        if self.open_window() && receiving {
+            self.send_ack(nic, now)?;
+        }
+        Ok(())
+    }
+
+    /// Moves the right edge of the receive window up to the free space in the receive buffer,
+    /// but only by at least an MSS or half the buffer, so the peer isn't invited to send tiny
+    /// segments (receiver-side SWS avoidance, RFC 9293, 3.8.6.2.2). Returns whether it moved.
+    fn open_window(&mut self) -> bool {
+        let buffer = Self::recv_buffer_for(&self.config);
+        let free = buffer.saturating_sub(self.incoming.len() as u32);
+        let threshold = std::cmp::min(buffer / 2, self.mss as u32);
+        if free >= self.recv.wnd.saturating_add(threshold) {
+            self.recv.wnd = free;
             true
-        } else  {
+        } else {
             false
         }
     }
 
-    fn availability(&self ) -> Available {
+    /// The receive buffer size, limited to the largest window that can be advertised.
+    fn recv_buffer_for(config: &Config) -> u32 {
+        let max = (u16::MAX as usize) << MAX_WSCALE;
+        std::cmp::min(config.recv_buffer, max) as u32
+    }
+
+    fn queue_fin(&mut self) {
+        self.closed_at = Some(self.send.una.wrapping_add(self.unacked.len() as u32));
+        self.state = match self.state {
+            State::Estab => State::FinWait1,
+            State::CloseWait => State::LastAck,
+            state => state,
+        };
+    }
+
+    /// Whether we have answered the peer's SYN but the handshake isn't complete yet.
+    pub(crate) fn is_half_open(&self) -> bool {
+        self.state == State::SynRcvd
+    }
+
+    pub(crate) fn is_synchronized(&self) -> bool {
+        self.state.is_synchronized()
+    }
+
+    /// How many more bytes the application may write before the send buffer is full.
+    pub(crate) fn send_space(&self) -> usize {
+        self.config.send_buffer.saturating_sub(self.unacked.len())
+    }
+
+    pub(crate) fn availability(&self ) -> Available {
         let mut a = Available::empty();
-        if self.is_rcv_closed() || !self.incoming.is_empty() {
+        if self.is_rcv_closed() || !self.incoming.is_empty() || self.error.is_some() {
             a |= Available::READ;
         }
+        if self.send_space() > 0 || self.error.is_some() {
+            a |= Available::WRITE;
+        }
         a
     }
 }
@@ -74,24 +393,131 @@ struct SendSequenceSpace {
     una: u32,
     // send next
     nxt: u32,
-    // send window
-    wnd: u16,
+    // one past the highest sequence number ever sent, which nxt falls behind while
+    // everything after una is sent again following a timeout
+    max: u32,
+    // send window, already scaled
+    wnd: u32,
+    // how far the peer's advertised windows are shifted (RFC 7323)
+    wscale: u8,
     // send urgent pointer
+    #[allow(dead_code)]
     up: bool,
-    // segment sequence number used for last window update 
-    wl1: usize,
-    // segment aknowledgment number used for last window update 
-    wl2: usize,
+    // segment sequence number used for last window update
+    wl1: u32,
+    // segment acknowledgment number used for last window update
+    wl2: u32,
     // initial send sequence number
     iss: u32
 }
 
+struct Timers {
+    // smoothed round-trip time, once we have taken a sample
+    srtt: Option<Duration>,
+    // round-trip time variation
+    rttvar: Duration,
+    // current retransmission timeout, including any backoff
+    rto: Duration,
+    // sequence number that acknowledges the segment being timed, and when it was sent
+    rtt_sample: Option<(u32, Instant)>,
+    // when the oldest unacknowledged segment is due for retransmission
+    rto_deadline: Option<Instant>,
+    // when TIME-WAIT is over
+    time_wait: Option<Instant>,
+    // when a detached connection stops waiting in FIN-WAIT-2 for the peer's FIN
+    fin_wait_2: Option<Instant>,
+    // when a delayed ACK must go out
+    ack_deadline: Option<Instant>,
+    // when the next keepalive probe is due
+    keepalive: Option<Instant>,
+    // keepalive probes sent since we last heard from the peer
+    keepalive_probes: u32,
+    // when the next window probe is due, while the peer's window is closed
+    persist: Option<Instant>,
+    // time between window probes, backed off like the RTO
+    persist_interval: Duration,
+    // window probes sent since we last heard from the peer
+    persist_probes: u32,
+    // consecutive retransmissions without the peer acknowledging anything new
+    retransmits: u32,
+}
+
+impl Timers {
+    fn new() -> Self {
+        Timers {
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: INITIAL_RTO,
+            rtt_sample: None,
+            rto_deadline: None,
+            time_wait: None,
+            fin_wait_2: None,
+            ack_deadline: None,
+            keepalive: None,
+            keepalive_probes: 0,
+            persist: None,
+            persist_interval: INITIAL_RTO,
+            persist_probes: 0,
+            retransmits: 0,
+        }
+    }
+
+    /// Folds a round-trip measurement into SRTT/RTTVAR and recomputes the RTO (RFC 6298, 2.2-2.3).
+    fn on_rtt_sample(&mut self, r: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(r);
+                self.rttvar = r / 2;
+            }
+            Some(srtt) => {
+                let delta = srtt.abs_diff(r);
+                self.rttvar = (self.rttvar * 3 + delta) / 4;
+                self.srtt = Some((srtt * 7 + r) / 8);
+            }
+        }
+        let srtt = self.srtt.unwrap();
+        self.rto = (srtt + std::cmp::max(CLOCK_GRANULARITY, self.rttvar * 4))
+            .clamp(MIN_RTO, MAX_RTO);
+    }
+}
+
+/// RFC 7323 timestamps, used for RTT measurement and PAWS.
+struct Timestamps {
+    // when our timestamp clock, which ticks in milliseconds, read zero
+    epoch: Instant,
+    // TS.Recent: the timestamp to echo back to the peer
+    recent: u32,
+    // when TS.Recent was last updated
+    recent_at: Instant,
+    // Last.ACK.sent: the acknowledgment number of the last segment we sent
+    last_ack_sent: u32,
+}
+
+impl Timestamps {
+    fn new(now: Instant) -> Self {
+        Timestamps {
+            epoch: now,
+            recent: 0,
+            recent_at: now,
+            last_ack_sent: 0,
+        }
+    }
+
+    /// TSval for a segment sent at `now`.
+    fn clock(&self, now: Instant) -> u32 {
+        now.saturating_duration_since(self.epoch).as_millis() as u32
+    }
+}
+
 struct RecvSequenceSpace {
     // receive next
     nxt: u32,
-    // receive window
-    wnd: u16,
+    // receive window: how far past nxt the window we last advertised reaches
+    wnd: u32,
+    // how far our advertised windows are shifted (RFC 7323)
+    wscale: u8,
     // receive urgent pointer
+    #[allow(dead_code)]
     up: bool,
     // initial receive sequence number
     irs: u32
@@ -112,156 +538,687 @@ impl Connection {
             }
         }
     }
+    fn wrapping_lt(lhs: u32, rhs: u32) -> bool {
+        // From RFC1323:
+        //     TCP determines if a data segment is "old" or "new" by testing
+        //     whether its sequence number is within 2**31 bytes of the left edge
+        //     of the window, and if it is not, discarding the data as "old".  To
+        //     insure that new data is never mistakenly considered old and vice-
+        //     versa, the left edge of the sender's window has to be at most
+        //     2**31 away from the right edge of the receiver's window.
+        lhs.wrapping_sub(rhs) > (1 << 31)
+    }
+
+    fn new(
+        quad: Quad,
+        iss: u32,
+        state: State,
+        config: &Config,
+        nic: &dyn PacketDevice,
+        now: Instant,
+    ) -> Self {
+        let wnd = Self::recv_buffer_for(config);
+        let local_mss = std::cmp::min(nic.mtu().saturating_sub(HEADERS_LEN), u16::MAX as usize) as u16;
+        let mss = std::cmp::min(local_mss, DEFAULT_MSS) as usize;
+        Connection {
+            state,
+            send: SendSequenceSpace {
+                // decide on stuff we're sending
+                iss,
+                una: iss,
+                nxt: iss,
+                max: iss,
+                wnd: 0,
+                wscale: 0,
+                up: false,
+                wl1: 0,
+                wl2: 0
+            },
+            recv: RecvSequenceSpace {
+                // filled in once we see the peer's SYN
+                irs: 0,
+                nxt: 0,
+                wnd,
+                wscale: 0,
+                up: false,
+            },
+            tcp: etherparse::TcpHeader::new(
+                quad.dst.1,
+                quad.src.1,
+                iss,
+                0),
+            ip: etherparse::Ipv4Header::new(
+                0,
+                64,
+                etherparse::IpTrafficClass::Tcp,
+                quad.dst.0.octets(),
+                quad.src.0.octets()),
+            config: config.clone(),
+            timers: Timers::new(),
+            cc: Congestion::new(mss as u32, iss),
+            local_mss,
+            mss,
+            wscale_offer: Some(Self::wscale_for(wnd)),
+            timestamps: Some(Timestamps::new(now)),
+            sack: true,
+            scoreboard: Scoreboard::default(),
+            nodelay: false,
+            rcv_unacked: 0,
+            quick_acks: QUICK_ACKS,
+            keepalive: None,
+            closed: false,
+            closed_at: None,
+            rd_closed: false,
+            out_of_order: Reassembly::default(),
+            incoming: Default::default(),
+            unacked: Default::default(),
+            error: None,
+            detached: false
+        }
+    }
+
+    fn quad_of(iph: &etherparse::Ipv4HeaderSlice, tcph: &etherparse::TcpHeaderSlice) -> Quad {
+        Quad {
+            src: (iph.source_addr(), tcph.source_port()),
+            dst: (iph.destination_addr(), tcph.destination_port()),
+        }
+    }
+
+    /// Runs a segment addressed to a listening port through the LISTEN state, returning
+    /// the new half-open connection, which starts its sequence numbers at `iss`, if it was
+    /// a SYN.
     pub fn accept<'a>(
-        nic: &mut tun_tap::Iface,
-        iph: etherparse::Ipv4HeaderSlice<'a>, 
+        nic: &dyn PacketDevice,
+        iph: etherparse::Ipv4HeaderSlice<'a>,
+        tcph: etherparse::TcpHeaderSlice<'a>,
+        data: &'a[u8],
+        config: &Config,
+        iss: u32,
+        now: Instant) -> io::Result<Option<Self>>{
+            let quad = Self::quad_of(&iph, &tcph);
+            let mut c = Connection::new(quad, iss, State::Listen, config, nic, now);
+            c.on_packet(nic, iph, tcph, data, now)?;
+            if let State::SynRcvd = c.state {
+                Ok(Some(c))
+            } else {
+                Ok(None)
+            }
+        }
+
+    /// Answers a SYN to a listening port whose half-open queue is full with a SYN-ACK whose
+    /// ISS is a cookie, keeping no state until the peer's ACK returns it. SYNs announcing an
+    /// MSS too small for a cookie to carry are dropped.
+    pub fn send_cookie<'a>(
+        nic: &dyn PacketDevice,
+        iph: etherparse::Ipv4HeaderSlice<'a>,
+        tcph: etherparse::TcpHeaderSlice<'a>,
+        data: &'a[u8],
+        config: &Config,
+        cookies: &mut SynCookies,
+        now: Instant) -> io::Result<()> {
+            let quad = Self::quad_of(&iph, &tcph);
+            let peer_mss = Options::parse(&tcph).mss.unwrap_or(DEFAULT_MSS);
+            let Some(iss) = cookies.make(&quad, tcph.sequence_number(), peer_mss, now) else {
+                // too small to encode; the peer will try again once the queue has room
+                return Ok(());
+            };
+            let mut c = Connection::new(quad, iss, State::Listen, config, nic, now);
+            // the cookie has no room to remember anything we might offer
+            c.wscale_offer = None;
+            c.timestamps = None;
+            c.sack = false;
+            c.on_packet(nic, iph, tcph, data, now)?;
+            Ok(())
+        }
+
+    /// Recreates the connection a SYN cookie was sent for, if `tcph` is an ACK that returns
+    /// a valid one, and runs the ACK through it.
+    pub fn accept_cookie<'a>(
+        nic: &dyn PacketDevice,
+        iph: etherparse::Ipv4HeaderSlice<'a>,
         tcph: etherparse::TcpHeaderSlice<'a>,
-        data: &'a[u8]) -> io::Result<Option<Self>>{
-            let mut buf = [0u8; 1500];
-            if !tcph.syn() {
-                // Only expected syn packet
+        data: &'a[u8],
+        config: &Config,
+        cookies: &mut SynCookies,
+        now: Instant) -> io::Result<Option<Self>> {
+            if !tcph.ack() || tcph.syn() || tcph.rst() {
                 return Ok(None);
             }
-            
-            let iss = 0;
-            let wnd = 10;
-            let mut c = Connection{
-                state: State:: SynRcvd,
-                send: SendSequenceSpace { 
-                    // decide on stuff we're sending
-                    iss,
-                    una: iss,
-                    nxt: iss + 1,
-                    wnd: wnd,
-                    up: false,
-                    wl1: 0,
-                    wl2: 0
-                },
-                recv: RecvSequenceSpace { 
-                    // keep track of sender info
-                    irs: tcph.sequence_number(),
-                    nxt: tcph.sequence_number() + 1,
-                    wnd: tcph.window_size(),
-                    up: false,
-                },
-                tcp: etherparse::TcpHeader::new (
-                    tcph.destination_port(), 
-                    tcph.source_port(),
-                    iss,
-                    wnd),
-                ip: etherparse::Ipv4Header::new(
-                    0, 
-                    64,
-                    etherparse::IpTrafficClass::Tcp,
-                    [
-                        iph.destination()[0],
-                        iph.destination()[1],
-                        iph.destination()[2],
-                        iph.destination()[3]
-                    ],
-                    [
-                        iph.source()[0],
-                        iph.source()[1],
-                        iph.source()[2],
-                        iph.source()[3]
-                    ]
-                    ),
-                incoming: Default::default(),
-                unacked: Default::default()
+            let quad = Self::quad_of(&iph, &tcph);
+            let irs = tcph.sequence_number().wrapping_sub(1);
+            let iss = tcph.acknowledgment_number().wrapping_sub(1);
+            let Some(peer_mss) = cookies.check(&quad, irs, iss, now) else {
+                return Ok(None);
             };
 
-            // need to start establishing a connection
-            
-            // syn_ack.acknowledgment_number = c.recv.nxt;
-            c.tcp.syn = true;
+            // pick up where sending the SYN-ACK left off in send_cookie
+            let mut c = Connection::new(quad, iss, State::SynRcvd, config, nic, now);
+            c.recv.irs = irs;
+            c.recv.nxt = irs.wrapping_add(1);
+            c.send.nxt = iss.wrapping_add(1);
+            c.send.max = c.send.nxt;
+            c.send.wnd = tcph.window_size() as u32;
+            c.send.wl1 = irs;
+            c.send.wl2 = iss;
             c.tcp.ack = true;
-            c.write(nic, &[])?;
-            // c.ip.set_payload_len(syn_ack.header_len() as usize + 0);
-            // let unwritten = {
-            //     let mut unwritten = &mut buf[..];
-            //     c.ip.write(&mut unwritten);
-            //     syn_ack.write(&mut unwritten);
-            //     unwritten.len();
-            // };
-            
-            // nic.send(&buf[..buf.len() - unwritten])?;
-            Ok(Some(c))
+            let options = Options {
+                mss: Some(peer_mss),
+                ..Options::default()
+            };
+            c.on_syn_options(&options, now);
+            c.on_packet(nic, iph, tcph, data, now)?;
+            if c.is_synchronized() {
+                Ok(Some(c))
+            } else {
+                Ok(None)
+            }
         }
-    
+
+    /// Answers a segment that belongs to no connection, as if from the CLOSED state.
+    pub fn refuse<'a>(
+        nic: &dyn PacketDevice,
+        iph: etherparse::Ipv4HeaderSlice<'a>,
+        tcph: etherparse::TcpHeaderSlice<'a>,
+        data: &'a[u8],
+        now: Instant) -> io::Result<()> {
+            let mut c = Connection::new(
+                Self::quad_of(&iph, &tcph),
+                0,
+                State::Closed,
+                &Config::default(),
+                nic,
+                now);
+            c.on_packet(nic, iph, tcph, data, now)?;
+            Ok(())
+        }
+
+    /// Actively opens a connection to `quad.src` by sending a SYN from `quad.dst` with
+    /// sequence number `iss`.
+    pub fn connect(
+        nic: &dyn PacketDevice,
+        quad: Quad,
+        config: &Config,
+        iss: u32,
+        now: Instant) -> io::Result<Self> {
+        let mut c = Connection::new(quad, iss, State::SynSent, config, nic, now);
+        c.write(nic, c.send.iss, 0, now)?;
+        Ok(c)
+    }
+
+    /// Sends the segment starting at `seq`, carrying at most `limit` bytes of `unacked`.
+    ///
+    /// SYN and FIN are set whenever `seq` covers them, so the same call both sends new
+    /// segments and retransmits old ones. Anything that occupies sequence space arms the
+    /// retransmission timer, even if the device fails to send it.
     fn write(
         &mut self,
-        nic: &mut tun_tap::Iface,
-        payload: &[u8]) -> io::Result<usize> {
-            let mut buf = [0u8; 1500];
-            self.tcp.sequence_number = self.send.nxt;
+        nic: &dyn PacketDevice,
+        seq: u32,
+        limit: usize,
+        now: Instant) -> io::Result<usize> {
+            self.tcp.sequence_number = seq;
             self.tcp.acknowledgment_number = self.recv.nxt;
-            let size = std::cmp::min(
-                buf.len(), 
-                self.tcp.header_len() as usize + self.ip.header_len() as usize + payload.len() as usize);
-            self.ip.set_payload_len(self.tcp.header_len() as usize + payload.len());
-
-
-            let mut unwritten = &mut buf[..];
-            self.ip.write(&mut unwritten);
-            self.tcp.write(&mut unwritten);
-            let payload_bytes = unwritten.write(payload)?;
-            let unwritten_len = unwritten.len();
-            self.send.nxt = self.send.nxt.wrapping_add(payload_bytes as u32) ;
+            if self.tcp.ack {
+                // this carries any ACK we were holding back
+                self.rcv_unacked = 0;
+                self.timers.ack_deadline = None;
+            }
+            self.tcp.syn = seq == self.send.iss
+                && matches!(self.state, State::SynSent | State::SynRcvd);
             if self.tcp.syn {
-                self.send.nxt = self.send.nxt.wrapping_add(1);
-                self.tcp.syn = false;
+                // the window in a SYN is never scaled
+                self.tcp.window_size = std::cmp::min(self.recv.wnd, u16::MAX as u32) as u16;
+            } else {
+                self.tcp.window_size = self.advertised_window();
+            }
+            let options = self.options(now);
+            self.tcp.set_options(&options).expect("tcp options fit in the header");
+
+            // the SYN never carries data, and only synchronized connections have data to send;
+            // bare ACKs and keepalive probes (whose seq lies before send.una) ask for none
+            let mut payload = Vec::new();
+            if !self.tcp.syn && self.state.is_synchronized() && limit > 0 {
+                let offset = seq.wrapping_sub(self.send.una) as usize;
+                // the MSS assumes a header without options
+                let max_payload = self.mss.saturating_sub(self.tcp.options_len()).max(1);
+                let len = limit
+                    .min(max_payload)
+                    .min(self.unacked.len().saturating_sub(offset));
+                payload.extend(self.unacked.range(offset..offset + len));
+            }
+            self.tcp.fin = self.closed_at == Some(seq.wrapping_add(payload.len() as u32));
+            // a segment the device fails to send is as good as lost on the way, and the
+            // retransmission timer armed below will send it again
+            let sent = self.send_raw(nic, &payload);
+            let payload_bytes = payload.len();
+
+            let mut next_seq = seq.wrapping_add(payload_bytes as u32);
+            if self.tcp.syn {
+                next_seq = next_seq.wrapping_add(1);
             }
             if self.tcp.fin {
-                self.send.nxt = self.send.nxt.wrapping_add(1);
-                self.tcp.fin = false;
+                next_seq = next_seq.wrapping_add(1);
             }
-             
-            nic.send(&buf[..buf.len() - unwritten_len])?;
+            if next_seq != seq {
+                if Self::wrapping_lt(self.send.nxt, next_seq) {
+                    self.send.nxt = next_seq;
+                }
+                if Self::wrapping_lt(self.send.max, next_seq) {
+                    // new data; time it unless we are already timing something
+                    self.send.max = next_seq;
+                    if self.timers.rtt_sample.is_none() {
+                        self.timers.rtt_sample = Some((next_seq, now));
+                    }
+                } else {
+                    // Karn's algorithm: never sample a retransmitted segment
+                    self.timers.rtt_sample = None;
+                }
+                if self.timers.rto_deadline.is_none() {
+                    self.timers.rto_deadline = Some(now + self.timers.rto);
+                }
+            }
+            self.tcp.syn = false;
+            self.tcp.fin = false;
+            self.tcp.set_options(&[]).expect("no options fit in the header");
+            sent?;
             Ok(payload_bytes)
     }
 
-    
-    // fn send(
-    //     &mut self,  
-    //     nic: &mut tun_tap::Iface, 
-    //     payload: &[u8]) -> io::Result<()> {
-    //         self.tcp.sequence_number = self.send.nxt;
-    //         self.tcp.acknowledgment_number = self.recv.nxt;
-    //         self.ip.set_payload_len(self.tcp.header_len()) + payload.len();
-    // }
+    /// The options of the segment being written: what we offer in a SYN, and the
+    /// timestamps and SACK blocks of everything after.
+    fn options(&mut self, now: Instant) -> Vec<etherparse::TcpOptionElement> {
+        use etherparse::TcpOptionElement::*;
+
+        let mut options = Vec::new();
+        if self.tcp.syn {
+            options.push(MaximumSegmentSize(self.local_mss));
+        }
+        if let Some(ts) = &mut self.timestamps {
+            if self.tcp.syn && self.sack {
+                // SACK-permitted takes the place of the padding (RFC 7323, appendix A)
+                options.push(SelectiveAcknowledgementPermitted);
+            } else {
+                options.push(Nop);
+                options.push(Nop);
+            }
+            options.push(Timestamp(ts.clock(now), ts.recent));
+            if self.tcp.ack {
+                ts.last_ack_sent = self.recv.nxt;
+            }
+        } else if self.tcp.syn && self.sack {
+            options.push(Nop);
+            options.push(Nop);
+            options.push(SelectiveAcknowledgementPermitted);
+        }
+        if self.tcp.syn {
+            if let Some(shift) = self.wscale_offer {
+                options.push(Nop);
+                options.push(WindowScale(shift));
+            }
+        } else if self.sack && self.state.is_synchronized() {
+            // 40 bytes of options leave room for three blocks next to a timestamp, four without
+            let max_blocks = if self.timestamps.is_some() { 3 } else { 4 };
+            let mut blocks = self.out_of_order.blocks(self.recv.nxt).into_iter().take(max_blocks);
+            if let Some(first) = blocks.next() {
+                let mut rest = [None; 3];
+                for (slot, block) in rest.iter_mut().zip(blocks) {
+                    *slot = Some(block);
+                }
+                options.push(Nop);
+                options.push(Nop);
+                options.push(SelectiveAcknowledgement(first, rest));
+            }
+        }
+        options
+    }
+
+    /// Puts `self.ip` and `self.tcp` on the wire as they are, followed by `payload`.
+    fn send_raw(&mut self, nic: &dyn PacketDevice, payload: &[u8]) -> io::Result<usize> {
+        let mut buf = vec![0u8; self.ip.header_len() + self.tcp.header_len() as usize + payload.len()];
+        self.ip
+            .set_payload_len(self.tcp.header_len() as usize + payload.len())
+            .expect("segment fits in an ip packet");
+        self.tcp.checksum = self.tcp
+            .calc_checksum_ipv4(&self.ip, payload)
+            .expect("failed to compute checksum");
+
+        let mut unwritten = &mut buf[..];
+        self.ip.write(&mut unwritten).expect("ip header fits in buffer");
+        self.tcp.write(&mut unwritten)?;
+        let payload_bytes = unwritten.write(payload)?;
+        let unwritten_len = unwritten.len();
+        nic.send(&buf[..buf.len() - unwritten_len])?;
+        Ok(payload_bytes)
+    }
+
+    fn send_ack(&mut self, nic: &dyn PacketDevice, now: Instant) -> io::Result<()> {
+        self.write(nic, self.send.nxt, 0, now)?;
+        Ok(())
+    }
+
+    /// Answers `tcph` with a reset, as described under "Reset Generation" in RFC 793.
     fn send_rst(
             &mut self,
-            nic: &mut tun_tap::Iface,
+            nic: &dyn PacketDevice,
+            tcph: &etherparse::TcpHeaderSlice,
+            slen: u32,
         ) -> io::Result<()> {
+            let ack = self.tcp.ack;
+            if tcph.ack() {
+                // <SEQ=SEG.ACK><CTL=RST>
+                self.tcp.sequence_number = tcph.acknowledgment_number();
+                self.tcp.acknowledgment_number = 0;
+                self.tcp.ack = false;
+            } else {
+                // <SEQ=0><ACK=SEG.SEQ+SEG.LEN><CTL=RST,ACK>
+                self.tcp.sequence_number = 0;
+                self.tcp.acknowledgment_number = tcph.sequence_number().wrapping_add(slen);
+                self.tcp.ack = true;
+            }
             self.tcp.rst = true;
-            // TODO: fix sequence numbers here
-            // TODO: handle synchronized Reset
-            self.tcp.sequence_number = 0;
-            self.tcp.acknowledgment_number = 0;
-            self.ip.set_payload_len(self.tcp.header_len() as usize);
-            self.write(nic,&[])?;
-            Ok(()) 
+            let r = self.send_raw(nic, &[]);
+            self.tcp.rst = false;
+            self.tcp.ack = ack;
+            r.map(|_| ())
         }
-    pub(crate) fn on_packet<'a>(
+
+    /// Processes an acceptable acknowledgment number: releases acknowledged bytes from the
+    /// retransmission queue, takes an RTT sample, restarts the retransmission timer and
+    /// lets congestion control grow the window.
+    fn on_ack(
         &mut self,
-        nic: &mut tun_tap::Iface,
-        iph: etherparse::Ipv4HeaderSlice<'a>,
-        tcph: etherparse::TcpHeaderSlice<'a>,
-        data: &'a[u8]
-    ) -> io::Result<Available>{
-        let ackn = tcph.acknowledgment_number();
-        if !Self::is_between_wrapped(
-            self.send.una, 
-            ackn, 
-            self.send.nxt.wrapping_add(1)) {
-            if !self.state.is_synchronized() {
-                // according to Reset Generation, we should send a RST
-                self.send_rst(nic);
+        nic: &dyn PacketDevice,
+        ackn: u32,
+        options: &Options,
+        now: Instant,
+    ) -> io::Result<()> {
+        if !Self::is_between_wrapped(self.send.una, ackn, self.send.max.wrapping_add(1)) {
+            return Ok(());
+        }
+
+        // SYN and FIN occupy sequence space but are not in the retransmission queue
+        let mut acked = ackn.wrapping_sub(self.send.una) as usize;
+        if self.send.una == self.send.iss {
+            acked -= 1;
+        }
+        if let Some(closed_at) = self.closed_at {
+            if ackn == closed_at.wrapping_add(1) {
+                acked -= 1;
             }
-            return Ok(self.availability())
         }
+        let acked = acked.min(self.unacked.len());
+        drop(self.unacked.drain(..acked));
         self.send.una = ackn;
-        let seqn = tcph.sequence_number();
+        if Self::wrapping_lt(self.send.nxt, ackn) {
+            // the peer had more than we have sent again since a timeout
+            self.send.nxt = ackn;
+        }
+
+        match (&self.timestamps, options.timestamp) {
+            (Some(ts), Some((_, tsecr))) => {
+                // the echoed timestamp tells exactly when the segment this acknowledges
+                // left, retransmission or not (RFC 7323, 4.1)
+                let rtt = ts.clock(now).wrapping_sub(tsecr);
+                self.timers.rtt_sample = None;
+                self.timers.on_rtt_sample(Duration::from_millis(rtt as u64));
+            }
+            _ => {
+                if let Some((end, sent)) = self.timers.rtt_sample {
+                    if !Self::wrapping_lt(ackn, end) {
+                        self.timers.rtt_sample = None;
+                        self.timers.on_rtt_sample(now - sent);
+                    }
+                }
+            }
+        }
+        self.timers.retransmits = 0;
+        self.timers.rto_deadline = if self.send.una == self.send.max {
+            None
+        } else {
+            Some(now + self.timers.rto)
+        };
+
+        let flight = self.send.nxt.wrapping_sub(self.send.una);
+        if self.cc.on_ack(ackn, acked as u32, flight, self.timers.srtt, now) {
+            self.retransmit_lost(nic, now)?;
+        }
+        Ok(())
+    }
+
+    /// Counts an ACK that acknowledges nothing new while data is outstanding, fast
+    /// retransmitting once enough of them have arrived (RFC 5681, 3.2). With SACK, every
+    /// further duplicate during recovery repairs the next hole (RFC 6675, 5).
+    fn on_dup_ack(&mut self, nic: &dyn PacketDevice, ackn: u32, now: Instant) -> io::Result<()> {
+        let flight = self.send.nxt.wrapping_sub(self.send.una);
+        let in_recovery = self.cc.in_recovery();
+        if self.cc.on_dup_ack(ackn, flight, self.send.max, now) {
+            self.scoreboard.start_recovery(self.send.una);
+            self.retransmit_lost(nic, now)?;
+        } else if in_recovery && !self.scoreboard.is_empty() {
+            self.retransmit_lost(nic, now)?;
+        }
+        Ok(())
+    }
+
+    /// Resends what the peer is missing: the next hole in the scoreboard when the peer
+    /// has selectively acknowledged anything, or else the oldest unacknowledged segment.
+    fn retransmit_lost(&mut self, nic: &dyn PacketDevice, now: Instant) -> io::Result<()> {
+        if self.scoreboard.is_empty() {
+            return self.retransmit(nic, now);
+        }
+        if let Some((seq, len)) = self.scoreboard.next_hole(self.send.una, self.mss as u32) {
+            self.write(nic, seq, len as usize, now)?;
+        }
+        Ok(())
+    }
+
+    /// The most data a segment can carry: the MSS less the options every data segment
+    /// carries with it (RFC 9293, 3.7.1).
+    fn segment_size(&self) -> usize {
+        // a timestamp and the two NOPs that align it
+        let options = if self.timestamps.is_some() { 12 } else { 0 };
+        self.mss.saturating_sub(options).max(1)
+    }
+
+    /// Resends the oldest unacknowledged segment.
+    fn retransmit(&mut self, nic: &dyn PacketDevice, now: Instant) -> io::Result<()> {
+        let inflight = self.send.max.wrapping_sub(self.send.una) as usize;
+        self.write(nic, self.send.una, std::cmp::min(inflight, self.mss), now)?;
+        Ok(())
+    }
+
+    /// Sends as much not-yet-sent data from `unacked` as the peer's window and the
+    /// congestion window allow, in
+    /// full-sized segments, followed by our FIN once it is queued. Returns the
+    /// number of segments sent.
+    ///
+    /// Unless `nodelay` is set, a segment smaller than a full one waits until everything sent
+    /// before it is acknowledged (Nagle's algorithm, RFC 896 and RFC 1122, 4.2.3.4).
+    pub(crate) fn transmit(&mut self, nic: &dyn PacketDevice, now: Instant) -> io::Result<usize> {
+        if !matches!(
+            self.state,
+            State::Estab | State::CloseWait | State::FinWait1 | State::Closing | State::LastAck
+        ) {
+            return Ok(0);
+        }
+        let mut segments = 0;
+        loop {
+            let in_flight = self.send.nxt.wrapping_sub(self.send.una) as usize;
+            let unsent = self.unacked.len().saturating_sub(in_flight);
+            let window = (std::cmp::min(self.send.wnd, self.cc.window()) as usize)
+                .saturating_sub(in_flight);
+            let n = unsent.min(window).min(self.segment_size());
+            if n == 0 {
+                break;
+            }
+            if n < self.segment_size() && in_flight > 0 && !self.nodelay && self.closed_at.is_none() {
+                // coalesce with later writes while the ACK is on its way
+                break;
+            }
+            // the last segment picks up the FIN by itself
+            let sent = self.write(nic, self.send.nxt, n, now)?;
+            segments += 1;
+            if sent == 0 {
+                break;
+            }
+        }
+        if self.closed_at == Some(self.send.nxt) {
+            // all data is out, but the FIN isn't
+            self.write(nic, self.send.nxt, 0, now)?;
+            segments += 1;
+        }
+
+        // with nothing in flight, no ACK will come to tell us the window has opened again,
+        // so we have to go and ask (RFC 9293, 3.8.6.1)
+        let unsent = self.unacked.len() > self.send.nxt.wrapping_sub(self.send.una) as usize;
+        if self.send.wnd == 0 && self.send.una == self.send.nxt && unsent {
+            if self.timers.persist.is_none() {
+                self.timers.persist_interval = self.timers.rto;
+                self.timers.persist = Some(now + self.timers.persist_interval);
+            }
+        } else {
+            self.timers.persist = None;
+        }
+        Ok(segments)
+    }
+
+    /// When `on_tick` next needs to run.
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        if self.error.is_some() || self.state == State::Closed {
+            return None;
+        }
+        [
+            self.timers.rto_deadline,
+            self.timers.time_wait,
+            self.timers.fin_wait_2,
+            self.timers.ack_deadline,
+            self.timers.keepalive,
+            self.timers.persist,
+        ]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+
+    /// Fires whichever of the connection's timers have expired by `now`.
+    pub(crate) fn on_tick(&mut self, nic: &dyn PacketDevice, now: Instant) -> io::Result<Available> {
+        if self.error.is_some() || self.state == State::Closed {
+            return Ok(self.availability());
+        }
+        if self.timers.time_wait.is_some_and(|deadline| deadline <= now) {
+            // 2MSL have passed; any stray segments of this incarnation are gone
+            self.timers.time_wait = None;
+            self.state = State::Closed;
+            return Ok(self.availability());
+        }
+        if self.timers.fin_wait_2.is_some_and(|deadline| deadline <= now) {
+            // the peer is never going to close its side
+            self.timers.fin_wait_2 = None;
+            self.state = State::Closed;
+            return Ok(self.availability());
+        }
+        if self.timers.rto_deadline.is_some_and(|deadline| deadline <= now) {
+            self.on_rto(nic, now)?;
+        }
+        if self.timers.ack_deadline.is_some_and(|deadline| deadline <= now) {
+            self.send_ack(nic, now)?;
+        }
+        if self.timers.keepalive.is_some_and(|deadline| deadline <= now) {
+            self.on_keepalive(nic, now)?;
+        }
+        if self.timers.persist.is_some_and(|deadline| deadline <= now) {
+            self.on_persist(nic, now)?;
+        }
+        Ok(self.availability())
+    }
+
+    /// Resends the oldest unacknowledged segment once the RTO has expired, backing the RTO
+    /// off exponentially (RFC 6298, 5.4-5.6). Everything sent after it counts as lost too,
+    /// and goes out again as ACKs open up the congestion window (RFC 5681, 3.1).
+    fn on_rto(&mut self, nic: &dyn PacketDevice, now: Instant) -> io::Result<()> {
+        if self.timers.retransmits >= MAX_RETRANSMITS {
+            // the peer is gone
+            self.error = Some(io::ErrorKind::TimedOut);
+            self.state = State::Closed;
+            self.timers.rto_deadline = None;
+            return Ok(());
+        }
+        self.timers.retransmits += 1;
+        self.timers.rto = std::cmp::min(self.timers.rto * 2, MAX_RTO);
+        self.timers.rto_deadline = None;
+        let flight = self.send.nxt.wrapping_sub(self.send.una);
+        self.cc.on_rto(flight, self.send.max, now);
+        // the peer may have dropped what it selectively acknowledged (RFC 2018, 8)
+        self.scoreboard.clear();
+        if self.state.is_synchronized() {
+            // go back N: transmit picks up again from wherever this retransmission ends
+            self.send.nxt = self.send.una;
+        }
+        self.retransmit(nic, now)
+    }
+
+    /// Probes a peer whose window is closed for an update, backing off exponentially like
+    /// the RTO, and gives up once as many probes as retransmissions have gone unanswered.
+    fn on_persist(&mut self, nic: &dyn PacketDevice, now: Instant) -> io::Result<()> {
+        if self.timers.persist_probes >= MAX_RETRANSMITS {
+            self.error = Some(io::ErrorKind::TimedOut);
+            self.state = State::Closed;
+            self.timers.persist = None;
+            return Ok(());
+        }
+        self.timers.persist_probes += 1;
+        self.timers.persist_interval = std::cmp::min(self.timers.persist_interval * 2, MAX_RTO);
+        self.timers.persist = Some(now + self.timers.persist_interval);
+        // like a keepalive probe, an old sequence number draws an ACK with the current window
+        self.write(nic, self.send.nxt.wrapping_sub(1), 0, now)?;
+        Ok(())
+    }
+
+    /// Probes a peer we haven't heard from in a while, or gives up on it once enough probes
+    /// have gone unanswered (RFC 1122, 4.2.3.6).
+    fn on_keepalive(&mut self, nic: &dyn PacketDevice, now: Instant) -> io::Result<()> {
+        if self.timers.keepalive_probes >= self.config.keepalive_probes {
+            self.error = Some(io::ErrorKind::TimedOut);
+            self.state = State::Closed;
+            self.timers.keepalive = None;
+            return Ok(());
+        }
+        self.timers.keepalive = Some(now + self.config.keepalive_interval);
+        if !matches!(self.state, State::Estab | State::CloseWait) || !self.unacked.is_empty() {
+            // the retransmission timer is watching the peer already, or there is nothing
+            // left to keep alive
+            return Ok(());
+        }
+        // an old sequence number, which the peer can only answer with an ACK
+        self.timers.keepalive_probes += 1;
+        self.write(nic, self.send.nxt.wrapping_sub(1), 0, now)?;
+        Ok(())
+    }
+
+    /// Marks the connection as no longer referred to by any stream; it goes away once closed,
+    /// and gives up on a peer that never sends its FIN.
+    pub(crate) fn detach(&mut self, now: Instant) {
+        self.detached = true;
+        if self.state == State::FinWait2 {
+            self.timers.fin_wait_2 = Some(now + self.config.fin_timeout);
+        }
+    }
+
+    fn enter_fin_wait_2(&mut self, now: Instant) {
+        self.state = State::FinWait2;
+        if self.detached {
+            self.timers.fin_wait_2 = Some(now + self.config.fin_timeout);
+        }
+    }
+
+    fn enter_time_wait(&mut self, now: Instant) {
+        self.state = State::TimeWait;
+        self.timers.fin_wait_2 = None;
+        self.timers.rto_deadline = None;
+        self.timers.time_wait = Some(now + self.config.msl * 2);
+    }
+
+    fn segment_len(tcph: &etherparse::TcpHeaderSlice, data: &[u8]) -> u32 {
         let mut slen = data.len() as u32;
         if tcph.fin() {
             slen += 1;
@@ -269,112 +1226,412 @@ impl Connection {
         if tcph.syn() {
             slen += 1;
         };
-        let wend = self.recv.nxt.wrapping_add(self.recv.wnd as u32);
-        let okay = if slen == 0 {
+        slen
+    }
+
+    /// Whether a segment occupying `slen` sequence numbers from `seqn` falls within the
+    /// receive window (RFC 793, "SEGMENT ARRIVES", first check).
+    fn is_acceptable(&self, seqn: u32, slen: u32) -> bool {
+        let wend = self.recv.nxt.wrapping_add(self.recv.wnd);
+        if slen == 0 {
             // zero length-segment has seperate rules for acceptance
             if self.recv.wnd == 0 {
-                if seqn != self.recv.nxt {
-                    false
-                } else {
-                    true
-                }
-            } else if !Self::is_between_wrapped(
-                self.recv.nxt.wrapping_sub(1), 
-                seqn, 
-                wend) {
-                false
+                seqn == self.recv.nxt
             } else {
-                true
-            } 
+                Self::is_between_wrapped(
+                    self.recv.nxt.wrapping_sub(1),
+                    seqn,
+                    wend)
+            }
         } else {
-            if self.recv.wnd == 0 {
-                false
-            } else if !Self::is_between_wrapped(self.recv.nxt.wrapping_sub(1), 
-                seqn,
-                wend) &&
-                !Self::is_between_wrapped(self.recv.nxt.wrapping_sub(1), 
-                seqn.wrapping_add(slen - 1),
-                wend) {
-                    false
-                } else {
-                    true
-                }       
-        };
+            self.recv.wnd != 0 &&
+                (Self::is_between_wrapped(self.recv.nxt.wrapping_sub(1),
+                    seqn,
+                    wend) ||
+                Self::is_between_wrapped(self.recv.nxt.wrapping_sub(1),
+                    seqn.wrapping_add(slen - 1),
+                    wend))
+        }
+    }
+
+    pub(crate) fn on_packet<'a>(
+        &mut self,
+        nic: &dyn PacketDevice,
+        _iph: etherparse::Ipv4HeaderSlice<'a>,
+        tcph: etherparse::TcpHeaderSlice<'a>,
+        data: &'a[u8],
+        now: Instant
+    ) -> io::Result<Available>{
+        let seqn = tcph.sequence_number();
+        let slen = Self::segment_len(&tcph, data);
+        let options = Options::parse(&tcph);
+        match self.state {
+            State::Closed => {
+                if !tcph.rst() {
+                    self.send_rst(nic, &tcph, slen)?;
+                }
+                return Ok(self.availability());
+            }
+            State::Listen => return self.on_listen(nic, tcph, &options, slen, now),
+            State::SynSent => return self.on_syn_sent(nic, tcph, &options, slen, now),
+            _ => {}
+        }
+
+        // first, check the timestamp against wrapped sequence numbers (RFC 7323, 5.3)...
+        if let (Some(ts), false) = (&self.timestamps, tcph.rst()) {
+            match options.timestamp {
+                // everything but a RST carries one once negotiated (RFC 7323, 3.2)
+                None => return Ok(self.availability()),
+                Some((tsval, _))
+                    if Self::wrapping_lt(tsval, ts.recent) && now - ts.recent_at < PAWS_IDLE =>
+                {
+                    self.send_ack(nic, now)?;
+                    return Ok(self.availability());
+                }
+                _ => {}
+            }
+        }
 
-        if !okay {
-            self.write(nic, &[]);
+        // ...and the sequence number
+        if !self.is_acceptable(seqn, slen) {
+            if !tcph.rst() {
+                self.send_ack(nic, now)?;
+            }
+            if self.state == State::TimeWait && tcph.fin() {
+                // our last ACK got lost, and the peer retransmitted its FIN
+                self.enter_time_wait(now);
+            }
             return Ok(self.availability());
         }
-        // valid segment check
+        self.on_peer_alive(now);
+        if let (Some(ts), Some((tsval, _))) = (&mut self.timestamps, options.timestamp) {
+            // remember the timestamp to echo, unless this segment is out of order (RFC 7323, 4.3)
+            let newer = !Self::wrapping_lt(tsval, ts.recent) || now - ts.recent_at >= PAWS_IDLE;
+            if newer && !Self::wrapping_lt(ts.last_ack_sent, seqn) {
+                ts.recent = tsval;
+                ts.recent_at = now;
+            }
+        }
 
-        self.recv.nxt = seqn.wrapping_add(slen);
-        if !tcph.ack() {
-            return Ok(self.availability())
+        // second, check the RST bit
+        if tcph.rst() {
+            self.error = match self.state {
+                State::SynRcvd => Some(io::ErrorKind::ConnectionRefused),
+                State::Estab | State::FinWait1 | State::FinWait2 | State::CloseWait => {
+                    Some(io::ErrorKind::ConnectionReset)
+                }
+                _ => None,
+            };
+            self.state = State::Closed;
+            return Ok(self.availability());
         }
 
+        // fourth, check the SYN bit (the third, security and precedence, doesn't apply)
+        if tcph.syn() {
+            // a SYN in the window is an error
+            self.error = Some(io::ErrorKind::ConnectionReset);
+            self.state = State::Closed;
+            self.send_rst(nic, &tcph, slen)?;
+            return Ok(self.availability());
+        }
+
+        // fifth, check the ACK field
+        if !tcph.ack() {
+            return Ok(self.availability());
+        }
         let ackn = tcph.acknowledgment_number();
         if let State::SynRcvd = self.state {
             if Self::is_between_wrapped(
-                self.send.una.wrapping_sub(1), 
-                ackn, 
+                self.send.una,
+                ackn,
                 self.send.nxt.wrapping_add(1)) {
-                    self.state = State::Estab;
-                } else {
-                    // TODO: RST: <SEQ=SEG.ACK><CTL=RST>
+                // must have ACKed our SYN, since we detected at least one acked byte, and have
+                // only sent one byte (the SYN)
+                self.state = State::Estab;
+            } else {
+                self.send_rst(nic, &tcph, slen)?;
+                return Ok(self.availability());
+            }
+        }
+        if Self::wrapping_lt(self.send.max, ackn) {
+            // acknowledges something we haven't sent yet
+            self.send_ack(nic, now)?;
+            return Ok(self.availability());
+        }
+        // RFC 5681, 2: acknowledges nothing new, carries nothing and leaves the window alone
+        let dup_ack = ackn == self.send.una
+            && self.send.una != self.send.max
+            && data.is_empty()
+            && !tcph.fin()
+            && self.peer_window(&tcph) == self.send.wnd;
+        if !Self::wrapping_lt(ackn, self.send.una) {
+            self.update_window(seqn, ackn, self.peer_window(&tcph));
+        }
+        if self.sack {
+            self.scoreboard.update(self.send.una, self.send.max, &options.sack);
+        }
+        // a retransmission the device fails to send is left to the timer, and the rest of
+        // the segment still needs processing
+        let retransmitted = if dup_ack {
+            self.on_dup_ack(nic, ackn, now)
+        } else {
+            self.on_ack(nic, ackn, &options, now)
+        };
+        if self.closed && self.closed_at.is_none() && self.state == State::Estab {
+            // the application closed before the handshake completed
+            self.queue_fin();
+        }
+        let fin_acked = self
+            .closed_at
+            .is_some_and(|closed_at| self.send.una == closed_at.wrapping_add(1));
+        match self.state {
+            State::FinWait1 if fin_acked => self.enter_fin_wait_2(now),
+            State::Closing if fin_acked => self.enter_time_wait(now),
+            State::LastAck if fin_acked => {
+                self.state = State::Closed;
+                return Ok(self.availability());
+            }
+            _ => {}
+        }
 
+        // seventh, process the segment text (the sixth, URG, we ignore)
+        let mut need_ack = false;
+        let mut delay_ack = false;
+        if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
+            if !data.is_empty() {
+                // RFC 5681 (4.2): only data that continues the stream without filling a gap
+                // may be acknowledged late
+                let in_order = seqn == self.recv.nxt && self.out_of_order.is_empty();
+                let nxt = self.recv.nxt;
+                self.on_data(seqn, data);
+                if in_order {
+                    self.rcv_unacked += self.recv.nxt.wrapping_sub(nxt) as usize;
+                    delay_ack = true;
+                } else {
+                    self.quick_acks = QUICK_ACKS;
+                    need_ack = true;
                 }
-            //expect to get an ACK for our SYN 
-            if !tcph.ack() {
-                return Ok(self.availability())
             }
-            // must have ACKed our SYN, since we detected at least one acked byte, and have
-            // only sent one byte (the SYN)
-            self.state = State::Estab;
-            
-            // now let's terminate the connection
-            // TODO: needs to be stored in the retransmission queue
-            self.tcp.fin = true;
-            self.write(nic, &[])?;
-            self.state = State::FinWait1;
         }
-        if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
-            if !Self::is_between_wrapped(
-                self.send.una, 
-                ackn, 
-                self.send.nxt.wrapping_add(1)) {
-                self.send.una = ackn;
-                //return Ok((self.availability()));
+
+        // eighth, check the FIN bit
+        if tcph.fin() && seqn.wrapping_add(data.len() as u32) == self.recv.nxt {
+            self.recv.nxt = self.recv.nxt.wrapping_add(1);
+            need_ack = true;
+            match self.state {
+                State::SynRcvd | State::Estab => self.state = State::CloseWait,
+                State::FinWait1 if !fin_acked => self.state = State::Closing,
+                State::FinWait1 | State::FinWait2 => self.enter_time_wait(now),
+                _ => {}
             }
-            // TODO: accept data
-            assert!(data.is_empty());
+        }
 
-            // TODO: needs to be stored in the retransmission queue
+        if delay_ack && !need_ack {
+            need_ack = self.should_ack_now(now);
+        }
 
-            if let State::Estab = self.state {
-                self.tcp.fin = true;
-                self.write(nic, &[])?;
-                self.state = State::FinWait1;
+        // an ACK may have opened up the window; anything we send carries our ACK along
+        if self.transmit(nic, now)? == 0 && need_ack {
+            self.send_ack(nic, now)?;
+        }
+        retransmitted?;
+        Ok(self.availability())
+    }
+
+    /// Takes the window of a segment with sequence number `seqn` acknowledging `ackn`, unless
+    /// an earlier segment is reordered behind a later one that updated the window already
+    /// (RFC 9293, 3.10.7.4).
+    fn update_window(&mut self, seqn: u32, ackn: u32, wnd: u32) {
+        let newer = Self::wrapping_lt(self.send.wl1, seqn)
+            || (self.send.wl1 == seqn && !Self::wrapping_lt(ackn, self.send.wl2));
+        if newer {
+            self.send.wnd = wnd;
+            self.send.wl1 = seqn;
+            self.send.wl2 = ackn;
+        }
+    }
+
+    /// Decides whether in-order data that just arrived is acknowledged right away, and
+    /// otherwise makes sure the delayed ACK timer is running (RFC 1122, 4.2.3.2).
+    fn should_ack_now(&mut self, now: Instant) -> bool {
+        if self.quick_acks > 0 {
+            self.quick_acks -= 1;
+            return true;
+        }
+        let delay = std::cmp::min(self.config.ack_delay, MAX_ACK_DELAY);
+        // at least every second full-sized segment is acknowledged (RFC 5681, 4.2)
+        if delay.is_zero() || self.rcv_unacked >= 2 * self.mss {
+            return true;
+        }
+        self.timers.ack_deadline.get_or_insert(now + delay);
+        false
+    }
+
+    /// Appends whatever part of `data` (which starts at `seqn`) is next in sequence and
+    /// inside the receive window to `incoming`, and holds on to anything that arrived early.
+    fn on_data(&mut self, seqn: u32, data: &[u8]) {
+        if self.rd_closed {
+            // still acknowledge it, so the peer isn't stuck retransmitting
+            let end = seqn.wrapping_add(data.len() as u32);
+            if Self::wrapping_lt(self.recv.nxt, end) && !Self::wrapping_lt(self.recv.nxt, seqn) {
+                self.recv.nxt = end;
             }
+            return;
+        }
+        if Self::wrapping_lt(self.recv.nxt, seqn) {
+            self.out_of_order.insert(self.recv.nxt, self.recv.wnd, seqn, data);
+            return;
+        }
+
+        // skip anything we have already received
+        let skip = self.recv.nxt.wrapping_sub(seqn) as usize;
+        if skip >= data.len() {
+            return;
+        }
+        let accept = std::cmp::min(data.len() - skip, self.recv.wnd as usize);
+        self.incoming.extend(&data[skip..skip + accept]);
+        self.recv.nxt = self.recv.nxt.wrapping_add(accept as u32);
+        // the right edge stays put until the application makes room
+        self.recv.wnd -= accept as u32;
 
+        // the gap in front of earlier out-of-order segments may now be filled
+        while let Some(run) = self.out_of_order.take(self.recv.nxt) {
+            self.incoming.extend(&run);
+            self.recv.nxt = self.recv.nxt.wrapping_add(run.len() as u32);
+            self.recv.wnd = self.recv.wnd.saturating_sub(run.len() as u32);
+        }
+    }
+
+    /// Adopts what the peer announced in its SYN.
+    fn on_syn_options(&mut self, options: &Options, now: Instant) {
+        let peer_mss = std::cmp::max(options.mss.unwrap_or(DEFAULT_MSS), MIN_MSS);
+        self.mss = std::cmp::min(self.local_mss, peer_mss) as usize;
+        self.cc.set_mss(self.mss as u32);
 
+        // windows are only scaled if both SYNs carry the option (RFC 7323, 2.2)
+        match options.wscale {
+            Some(shift) => {
+                self.send.wscale = std::cmp::min(shift, MAX_WSCALE);
+                self.recv.wscale = self.wscale_offer.unwrap_or(0);
+            }
+            None => self.wscale_offer = None,
         }
-        
-        if let  State::FinWait1 = self.state {
-            if !tcph.fin() || !data.is_empty() {
 
+        match (&mut self.timestamps, options.timestamp) {
+            (Some(ts), Some((tsval, _))) => {
+                ts.recent = tsval;
+                ts.recent_at = now;
             }
+            _ => self.timestamps = None,
         }
 
-        if tcph.fin() {
-            match self.state {
-                State::FinWait2 => {
-                    self.write(nic, &[])?;
-                    self.state = State::TimeWait;
-                }
-                _ => unimplemented!()
+        self.sack &= options.sack_permitted;
+    }
+
+    /// The smallest shift that lets a window of `wnd` bytes be advertised in full.
+    fn wscale_for(wnd: u32) -> u8 {
+        let mut shift = 0;
+        while shift < MAX_WSCALE && wnd >> shift > u16::MAX as u32 {
+            shift += 1;
+        }
+        shift
+    }
+
+    /// The window `tcph` advertises, in bytes.
+    fn peer_window(&self, tcph: &etherparse::TcpHeaderSlice) -> u32 {
+        (tcph.window_size() as u32) << self.send.wscale
+    }
+
+    /// The window field for a segment other than a SYN. Scaling rounds down, so we never
+    /// advertise more than we accept.
+    fn advertised_window(&self) -> u16 {
+        std::cmp::min(self.recv.wnd >> self.recv.wscale, u16::MAX as u32) as u16
+    }
+
+    /// Handles a segment for a port we are listening on (RFC 793, "LISTEN STATE").
+    fn on_listen(
+        &mut self,
+        nic: &dyn PacketDevice,
+        tcph: etherparse::TcpHeaderSlice,
+        options: &Options,
+        slen: u32,
+        now: Instant
+    ) -> io::Result<Available> {
+        if tcph.rst() {
+            return Ok(self.availability());
+        }
+        if tcph.ack() {
+            // nothing can be acknowledged yet
+            self.send_rst(nic, &tcph, slen)?;
+            return Ok(self.availability());
+        }
+        if !tcph.syn() {
+            return Ok(self.availability());
+        }
+
+        // keep track of sender info
+        self.recv.irs = tcph.sequence_number();
+        self.recv.nxt = tcph.sequence_number().wrapping_add(1);
+        self.send.wnd = tcph.window_size() as u32;
+        self.send.wl1 = tcph.sequence_number();
+        self.send.wl2 = self.send.iss;
+        self.on_syn_options(options, now);
+
+        // need to start establishing a connection
+        self.state = State::SynRcvd;
+        self.tcp.ack = true;
+        self.write(nic, self.send.iss, 0, now)?;
+        Ok(self.availability())
+    }
+
+    /// Handles a segment while our SYN is outstanding (RFC 793, "SYN-SENT STATE").
+    fn on_syn_sent(
+        &mut self,
+        nic: &dyn PacketDevice,
+        tcph: etherparse::TcpHeaderSlice,
+        options: &Options,
+        slen: u32,
+        now: Instant
+    ) -> io::Result<Available> {
+        let ackn = tcph.acknowledgment_number();
+        if tcph.ack() && !Self::is_between_wrapped(
+            self.send.iss,
+            ackn,
+            self.send.max.wrapping_add(1)) {
+            // acknowledges something we never sent
+            if !tcph.rst() {
+                self.send_rst(nic, &tcph, slen)?;
             }
+            return Ok(self.availability());
+        }
+        if tcph.rst() {
+            if tcph.ack() {
+                self.error = Some(io::ErrorKind::ConnectionRefused);
+                self.state = State::Closed;
+            }
+            return Ok(self.availability());
+        }
+        if !tcph.syn() {
+            return Ok(self.availability());
+        }
+
+        self.recv.irs = tcph.sequence_number();
+        self.recv.nxt = tcph.sequence_number().wrapping_add(1);
+        self.send.wnd = tcph.window_size() as u32;
+        self.send.wl1 = tcph.sequence_number();
+        self.send.wl2 = ackn;
+        self.on_syn_options(options, now);
+        self.tcp.ack = true;
+        if tcph.ack() {
+            // our SYN has been ACKed
+            self.state = State::Estab;
+            self.on_ack(nic, ackn, options, now)?;
+            self.send_ack(nic, now)?;
+        } else {
+            // simultaneous open: resend our SYN along with an ACK of theirs
+            self.state = State::SynRcvd;
+            self.write(nic, self.send.iss, 0, now)?;
         }
         Ok(self.availability())
     }
-    
-}
\ No newline at end of file
+}
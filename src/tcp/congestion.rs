@@ -0,0 +1,315 @@
+//! Congestion control: how much data a connection may have in flight.
+//!
+//! Loss detection and NewReno fast recovery (RFC 6582) are the same for every algorithm and
+//! live in [`Congestion`]. How the window grows and by how much it shrinks on loss is up to a
+//! [`CongestionControl`] implementation, which can be swapped per stream.
+
+use std::time::{Duration, Instant};
+
+use super::Connection;
+
+/// The initial congestion window for a given sender MSS (RFC 5681, 3.1).
+fn initial_window(mss: u32) -> u32 {
+    if mss > 2190 {
+        2 * mss
+    } else if mss > 1095 {
+        3 * mss
+    } else {
+        4 * mss
+    }
+}
+
+/// A connection's congestion window and slow start threshold, in bytes.
+///
+/// These belong to the connection rather than to the algorithm, so they carry over when
+/// the algorithm is changed mid-stream.
+#[derive(Clone, Debug)]
+pub struct CongestionWindow {
+    /// How many bytes may be in flight.
+    pub cwnd: u32,
+    /// Below this the window grows by slow start, above it by congestion avoidance.
+    pub ssthresh: u32,
+    /// Largest segment the connection sends.
+    pub mss: u32,
+}
+
+impl CongestionWindow {
+    /// Whether the window is still in slow start.
+    pub fn in_slow_start(&self) -> bool {
+        self.cwnd < self.ssthresh
+    }
+
+    /// Grows the window by slow start for `acked` newly acknowledged bytes (RFC 5681, 3.1).
+    pub fn slow_start(&mut self, acked: u32) {
+        self.cwnd = self.cwnd.saturating_add(std::cmp::min(acked, self.mss));
+    }
+}
+
+/// A congestion control algorithm, like those selected with `TCP_CONGESTION` on Linux.
+///
+/// The hooks are only called outside of fast recovery, which adjusts the window itself
+/// until the loss has been repaired.
+pub trait CongestionControl: Send {
+    /// Grows the window for an ACK of `acked` new bytes. `srtt` is the smoothed round-trip
+    /// time, once one has been measured.
+    fn on_ack(
+        &mut self,
+        window: &mut CongestionWindow,
+        acked: u32,
+        srtt: Option<Duration>,
+        now: Instant,
+    );
+
+    /// Shrinks the window when duplicate ACKs reveal a lost segment while `flight` bytes are
+    /// outstanding; `cwnd` must end up at the new `ssthresh`, from which fast recovery starts.
+    fn on_loss(&mut self, window: &mut CongestionWindow, flight: u32, now: Instant);
+
+    /// Collapses the window after a retransmission timeout.
+    fn on_rto(&mut self, window: &mut CongestionWindow, flight: u32, now: Instant);
+}
+
+/// The NewReno algorithm (RFC 5681), and the default.
+#[derive(Clone, Debug, Default)]
+pub struct NewReno;
+
+impl NewReno {
+    pub fn new() -> Self {
+        NewReno
+    }
+}
+
+impl CongestionControl for NewReno {
+    fn on_ack(&mut self, window: &mut CongestionWindow, acked: u32, _: Option<Duration>, _: Instant) {
+        if window.in_slow_start() {
+            window.slow_start(acked);
+        } else {
+            // congestion avoidance: about one MSS per round trip
+            let increase = std::cmp::max(1, window.mss * window.mss / window.cwnd);
+            window.cwnd = window.cwnd.saturating_add(increase);
+        }
+    }
+
+    fn on_loss(&mut self, window: &mut CongestionWindow, flight: u32, _: Instant) {
+        window.ssthresh = std::cmp::max(flight / 2, 2 * window.mss);
+        window.cwnd = window.ssthresh;
+    }
+
+    fn on_rto(&mut self, window: &mut CongestionWindow, flight: u32, _: Instant) {
+        window.ssthresh = std::cmp::max(flight / 2, 2 * window.mss);
+        window.cwnd = window.mss;
+    }
+}
+
+// RFC 9438, 4.2 and 4.6
+const CUBIC_C: f64 = 0.4;
+const CUBIC_BETA: f64 = 0.7;
+
+/// The CUBIC algorithm (RFC 9438), which grows the window as a cubic function of the time
+/// since the last loss rather than per round trip.
+#[derive(Clone, Debug, Default)]
+pub struct Cubic {
+    // window just before the last reduction, in segments
+    w_max: Option<f64>,
+    // start of the current congestion avoidance epoch
+    epoch: Option<Instant>,
+    // time the window takes to grow back to w_max, in seconds
+    k: f64,
+    // estimate of what Reno's window would be, in segments
+    w_est: f64,
+}
+
+impl Cubic {
+    pub fn new() -> Self {
+        Cubic::default()
+    }
+
+    /// W_cubic(t) (RFC 9438, 4.2), in segments.
+    fn w_cubic(&self, w_max: f64, t: f64) -> f64 {
+        CUBIC_C * (t - self.k).powi(3) + w_max
+    }
+
+    /// Remembers where the window was when loss struck, and resets the epoch (RFC 9438, 4.6-4.7).
+    fn reduce(&mut self, window: &mut CongestionWindow) {
+        let cwnd = window.cwnd as f64 / window.mss as f64;
+        // fast convergence: release bandwidth when the window stopped short of the last w_max
+        self.w_max = Some(match self.w_max {
+            Some(w_max) if cwnd < w_max => cwnd * (1.0 + CUBIC_BETA) / 2.0,
+            _ => cwnd,
+        });
+        self.epoch = None;
+        let ssthresh = (window.cwnd as f64 * CUBIC_BETA) as u32;
+        window.ssthresh = std::cmp::max(ssthresh, 2 * window.mss);
+    }
+}
+
+impl CongestionControl for Cubic {
+    fn on_ack(
+        &mut self,
+        window: &mut CongestionWindow,
+        acked: u32,
+        srtt: Option<Duration>,
+        now: Instant,
+    ) {
+        if window.in_slow_start() {
+            window.slow_start(acked);
+            return;
+        }
+
+        let mss = window.mss as f64;
+        let cwnd = window.cwnd as f64 / mss;
+        let epoch = match self.epoch {
+            Some(epoch) => epoch,
+            None => {
+                // first ACK of congestion avoidance since the last reduction
+                let w_max = *self.w_max.get_or_insert(cwnd);
+                self.k = if cwnd < w_max {
+                    ((w_max - cwnd) / CUBIC_C).cbrt()
+                } else {
+                    0.0
+                };
+                self.w_est = cwnd;
+                *self.epoch.insert(now)
+            }
+        };
+        let w_max = self.w_max.unwrap_or(cwnd);
+        let t = (now - epoch).as_secs_f64();
+        let rtt = srtt.unwrap_or(super::INITIAL_RTO).as_secs_f64();
+        let segments = acked as f64 / mss;
+
+        // Reno-friendly region (RFC 9438, 4.3)
+        let alpha = 3.0 * (1.0 - CUBIC_BETA) / (1.0 + CUBIC_BETA);
+        self.w_est += alpha * segments / cwnd;
+        if self.w_cubic(w_max, t) < self.w_est {
+            window.cwnd = std::cmp::max(window.cwnd, (self.w_est * mss) as u32);
+            return;
+        }
+
+        // concave and convex regions (RFC 9438, 4.4-4.5)
+        let target = self.w_cubic(w_max, t + rtt).clamp(cwnd, 1.5 * cwnd);
+        let increase = (target - cwnd) / cwnd * acked as f64;
+        window.cwnd = window.cwnd.saturating_add(increase as u32);
+    }
+
+    fn on_loss(&mut self, window: &mut CongestionWindow, _: u32, _: Instant) {
+        self.reduce(window);
+        window.cwnd = window.ssthresh;
+    }
+
+    fn on_rto(&mut self, window: &mut CongestionWindow, _: u32, _: Instant) {
+        self.reduce(window);
+        window.cwnd = window.mss;
+    }
+}
+
+/// Loss detection and NewReno fast recovery (RFC 5681, 3.2 and RFC 6582) around a
+/// pluggable [`CongestionControl`].
+pub(crate) struct Congestion {
+    window: CongestionWindow,
+    algorithm: Box<dyn CongestionControl>,
+    // duplicate ACKs in a row
+    dup_acks: u32,
+    // one past the highest sequence number sent when loss was last detected ("recover")
+    recover: u32,
+    in_recovery: bool,
+}
+
+impl Congestion {
+    pub(crate) fn new(mss: u32, iss: u32) -> Self {
+        Congestion {
+            window: CongestionWindow {
+                cwnd: initial_window(mss),
+                // arbitrarily high, so that slow start runs until the first loss
+                ssthresh: u32::MAX,
+                mss,
+            },
+            algorithm: Box::new(NewReno),
+            dup_acks: 0,
+            recover: iss,
+            in_recovery: false,
+        }
+    }
+
+    /// Sizes the window for the segment size settled on in the handshake.
+    pub(crate) fn set_mss(&mut self, mss: u32) {
+        self.window.mss = mss;
+        self.window.cwnd = initial_window(mss);
+    }
+
+    pub(crate) fn set_algorithm(&mut self, algorithm: Box<dyn CongestionControl>) {
+        self.algorithm = algorithm;
+    }
+
+    /// How many bytes may be in flight.
+    pub(crate) fn window(&self) -> u32 {
+        self.window.cwnd
+    }
+
+    /// Whether fast recovery is repairing a loss.
+    pub(crate) fn in_recovery(&self) -> bool {
+        self.in_recovery
+    }
+
+    /// Handles an ACK up to `ackn` that newly acknowledged `acked` bytes and left `flight`
+    /// bytes outstanding.
+    ///
+    /// Returns true for a partial acknowledgment during fast recovery, after which the
+    /// first unacknowledged segment must be retransmitted (RFC 6582, 3.2 step 3).
+    pub(crate) fn on_ack(
+        &mut self,
+        ackn: u32,
+        acked: u32,
+        flight: u32,
+        srtt: Option<Duration>,
+        now: Instant,
+    ) -> bool {
+        self.dup_acks = 0;
+        if !self.in_recovery {
+            self.algorithm.on_ack(&mut self.window, acked, srtt, now);
+            return false;
+        }
+        let w = &mut self.window;
+        if Connection::wrapping_lt(ackn, self.recover) {
+            // partial ACK: take back the acknowledged data, but let one new segment out
+            w.cwnd = w.cwnd.saturating_sub(acked);
+            if acked >= w.mss {
+                w.cwnd += w.mss;
+            }
+            return true;
+        }
+        // full ACK: everything outstanding at the time of the loss has arrived
+        w.cwnd = std::cmp::min(w.ssthresh, std::cmp::max(flight, w.mss) + w.mss);
+        self.in_recovery = false;
+        false
+    }
+
+    /// Counts a duplicate ACK of `ackn` while `flight` bytes are outstanding and `nxt` is the
+    /// next sequence number to send.
+    ///
+    /// Returns true on the third duplicate, when the first unacknowledged segment should be
+    /// fast retransmitted (RFC 6582, 3.2 step 2).
+    pub(crate) fn on_dup_ack(&mut self, ackn: u32, flight: u32, nxt: u32, now: Instant) -> bool {
+        if self.in_recovery {
+            // another segment has left the network
+            self.window.cwnd = self.window.cwnd.saturating_add(self.window.mss);
+            return false;
+        }
+        self.dup_acks += 1;
+        if self.dup_acks != 3 || Connection::wrapping_lt(ackn, self.recover) {
+            // too early, or still echoes of a loss we already recovered from
+            return false;
+        }
+        self.algorithm.on_loss(&mut self.window, flight, now);
+        self.window.cwnd = self.window.cwnd.saturating_add(3 * self.window.mss);
+        self.recover = nxt;
+        self.in_recovery = true;
+        true
+    }
+
+    /// Collapses the window after a retransmission timeout.
+    pub(crate) fn on_rto(&mut self, flight: u32, nxt: u32, now: Instant) {
+        self.algorithm.on_rto(&mut self.window, flight, now);
+        self.dup_acks = 0;
+        self.recover = nxt;
+        self.in_recovery = false;
+    }
+}
@@ -0,0 +1,140 @@
+//! SYN cookies (RFC 4987, 3.6): answering SYNs without keeping any state, by encoding
+//! what the connection needs into the ISS of the SYN-ACK.
+
+use std::time::{Duration, Instant};
+
+use super::siphash::SipKey;
+use super::Quad;
+
+// the MSS values a cookie can carry, indexed by three bits of it
+const MSS_TABLE: [u16; 8] = [216, 536, 1024, 1220, 1360, 1440, 1452, 1460];
+// cookies name the period they were made in, and stay valid into the next one
+const PERIOD: Duration = Duration::from_secs(64);
+
+/// Makes and checks SYN cookies with a key of its own.
+///
+/// A cookie is laid out like the ones of Linux and of the original scheme: the top five
+/// bits count periods of 64 seconds, the next three index the MSS, and the low 24 bits are a
+/// MAC (SipHash-2-4 under a secret key from the kernel) of the connection, the peer's ISN,
+/// the period and the MSS index.
+pub(crate) struct SynCookies {
+    key: SipKey,
+    // when the period count started at zero
+    epoch: Option<Instant>,
+}
+
+impl SynCookies {
+    /// How long after it was made a cookie may still come back valid.
+    pub(crate) const LIFETIME: Duration = Duration::from_secs(2 * PERIOD.as_secs());
+
+    pub(crate) fn new() -> Self {
+        SynCookies {
+            key: SipKey::random(),
+            epoch: None,
+        }
+    }
+
+    /// The ISS to answer a SYN on `quad` with, whose sequence number was `irs` and which
+    /// announced an MSS of `peer_mss`, or `None` if that MSS is below every one a cookie can
+    /// carry.
+    pub(crate) fn make(
+        &mut self,
+        quad: &Quad,
+        irs: u32,
+        peer_mss: u16,
+        now: Instant,
+    ) -> Option<u32> {
+        let period = self.period(now);
+        // round down, so we never send more than the peer asked for
+        let index = MSS_TABLE.iter().rposition(|&mss| mss <= peer_mss)? as u32;
+        Some(((period % 32) << 27) | (index << 24) | self.hash(quad, irs, period, index))
+    }
+
+    /// Checks that `iss` is a cookie we recently answered a SYN on `quad` with sequence number
+    /// `irs` with, and returns the MSS it carries.
+    pub(crate) fn check(&mut self, quad: &Quad, irs: u32, iss: u32, now: Instant) -> Option<u16> {
+        let current = self.period(now);
+        let age = current.wrapping_sub(iss >> 27) % 32;
+        if age > 1 {
+            return None;
+        }
+        let index = (iss >> 24) & 0b111;
+        let valid = (iss & 0x00ff_ffff) == self.hash(quad, irs, current.wrapping_sub(age), index);
+        valid.then_some(MSS_TABLE[index as usize])
+    }
+
+    fn period(&mut self, now: Instant) -> u32 {
+        let epoch = *self.epoch.get_or_insert(now);
+        (now.saturating_duration_since(epoch).as_secs() / PERIOD.as_secs()) as u32
+    }
+
+    fn hash(&self, quad: &Quad, irs: u32, period: u32, index: u32) -> u32 {
+        let mut message = [0u8; 24];
+        message[..4].copy_from_slice(&quad.src.0.octets());
+        message[4..6].copy_from_slice(&quad.src.1.to_be_bytes());
+        message[6..10].copy_from_slice(&quad.dst.0.octets());
+        message[10..12].copy_from_slice(&quad.dst.1.to_be_bytes());
+        message[12..16].copy_from_slice(&irs.to_be_bytes());
+        message[16..20].copy_from_slice(&period.to_be_bytes());
+        message[20..].copy_from_slice(&index.to_be_bytes());
+        self.key.hash(&message) as u32 & 0x00ff_ffff
+    }
+}
+
+impl Default for SynCookies {
+    fn default() -> Self {
+        SynCookies::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::time::Instant;
+
+    use super::{SynCookies, PERIOD};
+    use crate::tcp::Quad;
+
+    const QUAD: Quad = Quad {
+        src: (Ipv4Addr::new(10, 0, 0, 2), 40000),
+        dst: (Ipv4Addr::new(10, 0, 0, 1), 80),
+    };
+
+    #[test]
+    fn cookies_stay_valid_into_the_next_period() {
+        let mut cookies = SynCookies::new();
+        let start = Instant::now();
+        let cookie = cookies.make(&QUAD, 1000, 1460, start).unwrap();
+
+        assert_eq!(cookies.check(&QUAD, 1000, cookie, start), Some(1460));
+        assert_eq!(cookies.check(&QUAD, 1000, cookie, start + PERIOD), Some(1460));
+        assert_eq!(cookies.check(&QUAD, 1000, cookie, start + 2 * PERIOD), None);
+    }
+
+    #[test]
+    fn cookies_are_bound_to_the_connection() {
+        let mut cookies = SynCookies::new();
+        let now = Instant::now();
+        let cookie = cookies.make(&QUAD, 1000, 1460, now).unwrap();
+
+        assert_eq!(cookies.check(&QUAD, 1001, cookie, now), None);
+        let other = Quad {
+            src: (QUAD.src.0, QUAD.src.1 + 1),
+            ..QUAD
+        };
+        assert_eq!(cookies.check(&other, 1000, cookie, now), None);
+        // claiming another MSS breaks the MAC
+        let index = (cookie >> 24) & 0b111;
+        let forged = (cookie & !(0b111 << 24)) | ((index - 1) << 24);
+        assert_eq!(cookies.check(&QUAD, 1000, forged, now), None);
+    }
+
+    #[test]
+    fn cookies_round_the_mss_down() {
+        let mut cookies = SynCookies::new();
+        let now = Instant::now();
+        let cookie = cookies.make(&QUAD, 1000, 1400, now).unwrap();
+        assert_eq!(cookies.check(&QUAD, 1000, cookie, now), Some(1360));
+        assert_eq!(cookies.make(&QUAD, 1000, 100, now), None);
+    }
+}
@@ -0,0 +1,60 @@
+//! Initial sequence number selection (RFC 6528).
+
+use std::net::SocketAddrV4;
+use std::time::{Duration, Instant};
+
+use super::siphash::SipKey;
+
+// RFC 6528, 3: the clock component advances once every 4 microseconds
+const TICK: Duration = Duration::from_micros(4);
+
+/// Picks the initial send sequence number (ISS) of new connections.
+///
+/// Every `Interface` starts out with a [`KeyedIss`]; tests that need to know sequence
+/// numbers up front can swap in their own with `Interface::set_iss_generator`.
+pub trait IssGenerator: Send {
+    /// The ISS for a connection from `local` to `remote`, opened or accepted at `now`.
+    fn iss(&mut self, local: SocketAddrV4, remote: SocketAddrV4, now: Instant) -> u32;
+}
+
+/// The generator of RFC 6528: a keyed hash (SipHash-2-4, with a secret 128-bit key from the
+/// kernel) of the connection's addresses and ports, plus a clock ticking every 4 microseconds.
+///
+/// The hash keeps off-path attackers from guessing the sequence numbers of a connection,
+/// while the clock keeps successive incarnations of the same connection from reusing
+/// sequence numbers that old segments may still carry.
+pub struct KeyedIss {
+    key: SipKey,
+    // when the clock component started at zero
+    epoch: Option<Instant>,
+}
+
+impl KeyedIss {
+    /// Creates a generator with a fresh random key.
+    pub fn new() -> Self {
+        KeyedIss {
+            key: SipKey::random(),
+            epoch: None,
+        }
+    }
+}
+
+impl Default for KeyedIss {
+    fn default() -> Self {
+        KeyedIss::new()
+    }
+}
+
+impl IssGenerator for KeyedIss {
+    fn iss(&mut self, local: SocketAddrV4, remote: SocketAddrV4, now: Instant) -> u32 {
+        let mut message = [0u8; 12];
+        message[..4].copy_from_slice(&local.ip().octets());
+        message[4..6].copy_from_slice(&local.port().to_be_bytes());
+        message[6..10].copy_from_slice(&remote.ip().octets());
+        message[10..].copy_from_slice(&remote.port().to_be_bytes());
+        let hash = self.key.hash(&message) as u32;
+        let epoch = *self.epoch.get_or_insert(now);
+        let ticks = (now.saturating_duration_since(epoch).as_nanos() / TICK.as_nanos()) as u32;
+        hash.wrapping_add(ticks)
+    }
+}
@@ -0,0 +1,41 @@
+use etherparse::TcpOptionElement;
+
+/// The TCP options of a received segment that we act on.
+#[derive(Default)]
+pub(crate) struct Options {
+    // the largest segment the peer is willing to receive
+    pub(crate) mss: Option<u16>,
+    // the shift the peer applies to the windows it advertises
+    pub(crate) wscale: Option<u8>,
+    // TSval and TSecr
+    pub(crate) timestamp: Option<(u32, u32)>,
+    // whether the peer is willing to receive SACK blocks
+    pub(crate) sack_permitted: bool,
+    // [start, end) of each block the peer has selectively acknowledged
+    pub(crate) sack: Vec<(u32, u32)>,
+}
+
+impl Options {
+    /// Collects the options of `tcph`, stopping at the first one that cannot be parsed.
+    pub(crate) fn parse(tcph: &etherparse::TcpHeaderSlice) -> Self {
+        let mut options = Options::default();
+        for option in tcph.options_iterator() {
+            match option {
+                Ok(TcpOptionElement::MaximumSegmentSize(mss)) => options.mss = Some(mss),
+                Ok(TcpOptionElement::WindowScale(shift)) => options.wscale = Some(shift),
+                Ok(TcpOptionElement::Timestamp(tsval, tsecr)) => {
+                    options.timestamp = Some((tsval, tsecr))
+                }
+                Ok(TcpOptionElement::SelectiveAcknowledgementPermitted) => {
+                    options.sack_permitted = true
+                }
+                Ok(TcpOptionElement::SelectiveAcknowledgement(first, rest)) => {
+                    options.sack = std::iter::once(first).chain(rest.iter().flatten().copied()).collect()
+                }
+                Ok(_) => {}
+                Err(_) => break,
+            }
+        }
+        options
+    }
+}
@@ -0,0 +1,110 @@
+use std::collections::VecDeque;
+
+use super::Connection;
+
+/// Segments that arrived ahead of `recv.nxt`, held until the gap in front of them fills.
+///
+/// Runs are kept sorted, disjoint and non-adjacent. Every run lies inside the receive
+/// window, so ordering them by their distance from `recv.nxt` is safe across wraparound.
+#[derive(Default)]
+pub(crate) struct Reassembly {
+    runs: VecDeque<(u32, Vec<u8>)>,
+    // a sequence number of the most recently stored data, for reporting its run first
+    latest: Option<u32>,
+}
+
+impl Reassembly {
+    /// Stores the part of `data` (which starts at `seq`) that falls within `[nxt, nxt + wnd)`,
+    /// merging it with any runs it overlaps or touches.
+    pub(crate) fn insert(&mut self, nxt: u32, wnd: u32, seq: u32, data: &[u8]) {
+        // work in offsets from nxt, which are ordered for everything inside the window
+        let offset = |seq: u32| seq.wrapping_sub(nxt);
+        let (data_start, data) = if Connection::wrapping_lt(seq, nxt) {
+            let skip = nxt.wrapping_sub(seq) as usize;
+            if skip >= data.len() {
+                return;
+            }
+            (0, &data[skip..])
+        } else {
+            (offset(seq), data)
+        };
+        if data_start >= wnd {
+            return;
+        }
+        let data_end = std::cmp::min(data_start as u64 + data.len() as u64, wnd as u64) as u32;
+        let data = &data[..(data_end - data_start) as usize];
+
+        // runs[first..last] overlap or touch the new data
+        let first = self
+            .runs
+            .iter()
+            .position(|(s, d)| offset(*s) + d.len() as u32 >= data_start)
+            .unwrap_or(self.runs.len());
+        let last = first + self
+            .runs
+            .iter()
+            .skip(first)
+            .take_while(|(s, _)| offset(*s) <= data_end)
+            .count();
+
+        let (mut start, mut end) = (data_start, data_end);
+        if first < last {
+            start = std::cmp::min(start, offset(self.runs[first].0));
+            let (s, d) = &self.runs[last - 1];
+            end = std::cmp::max(end, offset(*s) + d.len() as u32);
+        }
+
+        let mut merged = vec![0; (end - start) as usize];
+        for (s, d) in self.runs.drain(first..last) {
+            let at = (offset(s) - start) as usize;
+            merged[at..at + d.len()].copy_from_slice(&d);
+        }
+        let at = (data_start - start) as usize;
+        merged[at..at + data.len()].copy_from_slice(data);
+        self.runs.insert(first, (nxt.wrapping_add(start), merged));
+        self.latest = Some(nxt.wrapping_add(data_start));
+    }
+
+    /// The `[start, end)` ranges held beyond `nxt`, as SACK blocks: the run holding the most
+    /// recently stored data first, then the rest in sequence order (RFC 2018, 4).
+    pub(crate) fn blocks(&self, nxt: u32) -> Vec<(u32, u32)> {
+        let offset = |seq: u32| seq.wrapping_sub(nxt);
+        let mut blocks: Vec<(u32, u32)> = self
+            .runs
+            .iter()
+            .map(|(s, d)| (*s, s.wrapping_add(d.len() as u32)))
+            .filter(|&(_, end)| Connection::wrapping_lt(nxt, end))
+            .collect();
+        let latest = self.latest.and_then(|latest| {
+            blocks
+                .iter()
+                .position(|&(s, e)| offset(s) <= offset(latest) && offset(latest) < offset(e))
+        });
+        if let Some(i) = latest {
+            let block = blocks.remove(i);
+            blocks.insert(0, block);
+        }
+        blocks
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.runs.is_empty()
+    }
+
+    /// Removes and returns the data that continues the stream at `nxt`, if any has arrived.
+    pub(crate) fn take(&mut self, nxt: u32) -> Option<Vec<u8>> {
+        while let Some((seq, data)) = self.runs.pop_front() {
+            if Connection::wrapping_lt(nxt, seq) {
+                // there is still a gap
+                self.runs.push_front((seq, data));
+                return None;
+            }
+            let skip = nxt.wrapping_sub(seq) as usize;
+            if skip < data.len() {
+                return Some(data[skip..].to_vec());
+            }
+            // everything in this run has since been received in order
+        }
+        None
+    }
+}
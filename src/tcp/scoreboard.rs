@@ -0,0 +1,91 @@
+use super::Connection;
+
+/// What the peer has told us through SACK blocks that it holds beyond `send.una`, and what
+/// we have retransmitted since (RFC 6675).
+///
+/// Ranges are kept sorted, disjoint and within `(send.una, send.nxt]`, so ordering them by
+/// their distance from `send.una` is safe across wraparound.
+#[derive(Default)]
+pub(crate) struct Scoreboard {
+    // [start, end) of each range the peer has selectively acknowledged
+    sacked: Vec<(u32, u32)>,
+    // HighRxt: one past the last byte retransmitted during the current recovery
+    high_rxt: u32,
+}
+
+impl Scoreboard {
+    /// Records the SACK blocks of an ACK, ignoring any that don't lie within `[una, nxt]`.
+    pub(crate) fn update(&mut self, una: u32, nxt: u32, blocks: &[(u32, u32)]) {
+        self.prune(una);
+        let offset = |seq: u32| seq.wrapping_sub(una);
+        let mut ranges: Vec<(u32, u32)> = self
+            .sacked
+            .iter()
+            .map(|&(start, end)| (offset(start), offset(end)))
+            .collect();
+        for &(start, end) in blocks {
+            if offset(start) < offset(end) && offset(end) <= offset(nxt) {
+                ranges.push((offset(start), offset(end)));
+            }
+        }
+        ranges.sort_unstable();
+
+        self.sacked.clear();
+        for (start, end) in ranges {
+            match self.sacked.last_mut() {
+                Some(last) if offset(last.1) >= start => {
+                    last.1 = una.wrapping_add(std::cmp::max(offset(last.1), end));
+                }
+                _ => self.sacked.push((una.wrapping_add(start), una.wrapping_add(end))),
+            }
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.sacked.is_empty()
+    }
+
+    /// Forgets everything, as the peer may have discarded what it selectively acknowledged.
+    pub(crate) fn clear(&mut self) {
+        self.sacked.clear();
+    }
+
+    /// Starts tracking retransmissions for a new loss recovery.
+    pub(crate) fn start_recovery(&mut self, una: u32) {
+        self.high_rxt = una;
+    }
+
+    /// The next range of at most `mss` bytes that the peer is missing and we haven't
+    /// retransmitted yet in this recovery, marked as retransmitted. Only gaps below the
+    /// highest selectively acknowledged byte count as missing.
+    pub(crate) fn next_hole(&mut self, una: u32, mss: u32) -> Option<(u32, u32)> {
+        self.prune(una);
+        let from = if Connection::wrapping_lt(self.high_rxt, una) {
+            una
+        } else {
+            self.high_rxt
+        };
+        let mut cursor = from;
+        for &(start, end) in &self.sacked {
+            if Connection::wrapping_lt(cursor, start) {
+                let len = std::cmp::min(start.wrapping_sub(cursor), mss);
+                self.high_rxt = cursor.wrapping_add(len);
+                return Some((cursor, len));
+            }
+            if Connection::wrapping_lt(cursor, end) {
+                cursor = end;
+            }
+        }
+        None
+    }
+
+    /// Drops whatever has since been cumulatively acknowledged.
+    fn prune(&mut self, una: u32) {
+        self.sacked.retain(|&(_, end)| Connection::wrapping_lt(una, end));
+        if let Some(first) = self.sacked.first_mut() {
+            if Connection::wrapping_lt(first.0, una) {
+                first.0 = una;
+            }
+        }
+    }
+}
@@ -0,0 +1,103 @@
+//! SipHash-2-4 (Aumasson and Bernstein, "SipHash: a fast short-input PRF", 2012), the keyed
+//! pseudorandom function behind initial sequence numbers and SYN cookies.
+
+/// A secret 128-bit SipHash key.
+pub(crate) struct SipKey {
+    k0: u64,
+    k1: u64,
+}
+
+impl SipKey {
+    /// A fresh key from the kernel's random number generator.
+    pub(crate) fn random() -> Self {
+        let mut key = [0u8; 16];
+        let mut filled = 0;
+        while filled < key.len() {
+            let rest = &mut key[filled..];
+            let n = unsafe { libc::getrandom(rest.as_mut_ptr().cast(), rest.len(), 0) };
+            if n < 0 {
+                let e = std::io::Error::last_os_error();
+                if e.kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                panic!("failed to read a random key: {}", e);
+            }
+            filled += n as usize;
+        }
+        SipKey {
+            k0: u64::from_le_bytes(key[..8].try_into().unwrap()),
+            k1: u64::from_le_bytes(key[8..].try_into().unwrap()),
+        }
+    }
+
+    /// The SipHash-2-4 of `message` under this key.
+    pub(crate) fn hash(&self, message: &[u8]) -> u64 {
+        let mut v = [
+            self.k0 ^ 0x736f_6d65_7073_6575,
+            self.k1 ^ 0x646f_7261_6e64_6f6d,
+            self.k0 ^ 0x6c79_6765_6e65_7261,
+            self.k1 ^ 0x7465_6462_7974_6573,
+        ];
+
+        let mut chunks = message.chunks_exact(8);
+        for chunk in &mut chunks {
+            let m = u64::from_le_bytes(chunk.try_into().unwrap());
+            v[3] ^= m;
+            sip_rounds(&mut v, 2);
+            v[0] ^= m;
+        }
+        // the last block holds what is left over, and the message length in its top byte
+        let mut last = [0u8; 8];
+        let rest = chunks.remainder();
+        last[..rest.len()].copy_from_slice(rest);
+        last[7] = message.len() as u8;
+        let m = u64::from_le_bytes(last);
+        v[3] ^= m;
+        sip_rounds(&mut v, 2);
+        v[0] ^= m;
+
+        v[2] ^= 0xff;
+        sip_rounds(&mut v, 4);
+        v[0] ^ v[1] ^ v[2] ^ v[3]
+    }
+}
+
+fn sip_rounds(v: &mut [u64; 4], rounds: usize) {
+    for _ in 0..rounds {
+        v[0] = v[0].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(13);
+        v[1] ^= v[0];
+        v[0] = v[0].rotate_left(32);
+        v[2] = v[2].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(16);
+        v[3] ^= v[2];
+        v[0] = v[0].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(21);
+        v[3] ^= v[0];
+        v[2] = v[2].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(17);
+        v[1] ^= v[2];
+        v[2] = v[2].rotate_left(32);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SipKey;
+
+    // the reference vectors from the appendix of the SipHash paper: key 00 01 .. 0f, message
+    // 00 01 .. (len - 1)
+    fn reference(len: u8) -> u64 {
+        let key = SipKey {
+            k0: 0x0706_0504_0302_0100,
+            k1: 0x0f0e_0d0c_0b0a_0908,
+        };
+        key.hash(&(0..len).collect::<Vec<u8>>())
+    }
+
+    #[test]
+    fn matches_the_reference_vectors() {
+        assert_eq!(reference(0), 0x726f_db47_dd0e_0e31);
+        assert_eq!(reference(15), 0xa129_ca61_49be_45e5);
+    }
+}
@@ -0,0 +1,909 @@
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddrV4};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use trust::sim::{LinkConfig, Network, SimDevice};
+use etherparse::TcpOptionElement;
+use trust::{
+    Config, Cubic, Interface, IssGenerator, PacketDevice, TcpListener, TcpStream,
+};
+
+const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+fn payload(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+/// Sends `request` from a client to a server that replies with `response`, each side
+/// closing its half once done, and returns what each side read.
+fn exchange(net: &Network, request: &[u8], response: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let mut client = Interface::with_device(net.device(CLIENT), CLIENT);
+    let mut server = Interface::with_device(net.device(SERVER), SERVER);
+    let mut listener = server.bind(80).unwrap();
+
+    let response = response.to_vec();
+    let server_side = thread::spawn(move || {
+        let mut stream = listener.accept().unwrap();
+        let mut request = Vec::new();
+        stream.read_to_end(&mut request).unwrap();
+        stream.write_all(&response).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        stream.flush().unwrap();
+        request
+    });
+
+    let mut stream = client.connect(SERVER, 80).unwrap();
+    stream.write_all(request).unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    drop(stream);
+
+    let request = server_side.join().unwrap();
+    drop(client);
+    drop(server);
+    (request, response)
+}
+
+#[test]
+fn transfer_over_perfect_link() {
+    let net = Network::new(1, LinkConfig::default());
+    let (request, response) = exchange(&net, &payload(1000), b"thanks");
+    assert_eq!(request, payload(1000));
+    assert_eq!(response, b"thanks");
+}
+
+#[test]
+fn transfer_over_impaired_link() {
+    for seed in 0..3 {
+        let net = Network::new(
+            seed,
+            LinkConfig {
+                loss: 0.1,
+                duplicate: 0.05,
+                corrupt: 0.05,
+                reorder: 0.1,
+                ..LinkConfig::default()
+            },
+        );
+        let (request, response) = exchange(&net, &payload(500), &payload(300));
+        assert_eq!(request, payload(500), "seed {}", seed);
+        assert_eq!(response, payload(300), "seed {}", seed);
+    }
+}
+
+#[test]
+fn transfer_with_cubic() {
+    let net = Network::new(
+        7,
+        LinkConfig {
+            loss: 0.1,
+            ..LinkConfig::default()
+        },
+    );
+    let mut client = Interface::with_device(net.device(CLIENT), CLIENT);
+    let mut server = Interface::with_device(net.device(SERVER), SERVER);
+    let mut listener = server.bind(80).unwrap();
+
+    let server_side = thread::spawn(move || {
+        let mut stream = listener.accept().unwrap();
+        let mut request = Vec::new();
+        stream.read_to_end(&mut request).unwrap();
+        request
+    });
+
+    let mut stream = client.connect(SERVER, 80).unwrap();
+    stream.set_congestion_control(Cubic::new()).unwrap();
+    stream.write_all(&payload(800)).unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    stream.flush().unwrap();
+    assert_eq!(server_side.join().unwrap(), payload(800));
+
+    drop(stream);
+    drop(client);
+    drop(server);
+}
+
+#[test]
+fn transfer_fills_a_long_pipe() {
+    let net = Network::new(
+        1,
+        LinkConfig {
+            latency: Duration::from_millis(50),
+            ..LinkConfig::default()
+        },
+    );
+    let start = net.now();
+    let (request, _) = exchange(&net, &payload(200_000), b"thanks");
+    assert_eq!(request, payload(200_000));
+    // a few round trips of slow start, not one round trip per buffer of a few segments
+    assert!(net.now() - start < Duration::from_secs(2), "took {:?}", net.now() - start);
+}
+
+#[test]
+fn transfer_survives_a_link_that_refuses_packets() {
+    let net = Network::new(1, LinkConfig::default());
+    let mut client = Interface::with_device(net.device(CLIENT), CLIENT);
+    let mut server = Interface::with_device(net.device(SERVER), SERVER);
+    let mut listener = server.bind(80).unwrap();
+
+    let server_side = thread::spawn(move || {
+        let mut stream = listener.accept().unwrap();
+        let mut request = vec![0u8; 1000];
+        stream.read_exact(&mut request).unwrap();
+        request
+    });
+
+    let mut stream = client.connect(SERVER, 80).unwrap();
+    // for a few seconds, every segment with data in it is too big for the link
+    net.set_config(LinkConfig { mtu: 100, ..LinkConfig::default() });
+    stream.write_all(&payload(1000)).unwrap();
+    let clock = net.device(Ipv4Addr::new(10, 0, 0, 3));
+    clock.recv(&mut [0u8; 1500], Some(Duration::from_secs(3))).unwrap_err();
+    drop(clock);
+    net.set_config(LinkConfig::default());
+
+    assert_eq!(server_side.join().unwrap(), payload(1000));
+    drop(stream);
+    drop(client);
+    drop(server);
+}
+
+#[test]
+fn connect_to_closed_port_is_refused() {
+    let net = Network::new(1, LinkConfig::default());
+    let mut client = Interface::with_device(net.device(CLIENT), CLIENT);
+    let _server = Interface::with_device(net.device(SERVER), SERVER);
+    let err = client.connect(SERVER, 80).map(drop).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+}
+
+/// A server on a zero-latency network of its own, listening on port 80, with a scripted
+/// peer at `CLIENT` to drive it.
+///
+/// The fields drop in order, so the peer is detached before the server needs virtual time
+/// to pass to shut down, even when a test fails halfway.
+struct Scripted {
+    peer: SimDevice,
+    listener: TcpListener,
+    server: Interface,
+    net: Network,
+}
+
+fn scripted_server(config: Config) -> Scripted {
+    let link = LinkConfig {
+        latency: Duration::ZERO,
+        ..LinkConfig::default()
+    };
+    scripted_server_on(link, config)
+}
+
+fn scripted_server_on(link: LinkConfig, config: Config) -> Scripted {
+    let net = Network::new(1, link);
+    let peer = net.device(CLIENT);
+    let mut server = Interface::with_device(net.device(SERVER), SERVER);
+    server.set_config(config);
+    let listener = server.bind(80).unwrap();
+    Scripted { peer, listener, server, net }
+}
+
+/// A segment from the scripted client to the server.
+struct Segment<'a> {
+    port: u16,
+    seq: u32,
+    ack: Option<u32>,
+    syn: bool,
+    rst: bool,
+    window: u16,
+    options: &'a [TcpOptionElement],
+    payload: &'a [u8],
+}
+
+impl Default for Segment<'_> {
+    fn default() -> Self {
+        Segment {
+            port: 40000,
+            seq: 0,
+            ack: None,
+            syn: false,
+            rst: false,
+            window: 65535,
+            options: &[],
+            payload: &[],
+        }
+    }
+}
+
+impl Segment<'_> {
+    fn build(&self) -> Vec<u8> {
+        let mut builder = etherparse::PacketBuilder::ipv4(CLIENT.octets(), SERVER.octets(), 64)
+            .tcp(self.port, 80, self.seq, self.window);
+        if self.syn {
+            builder = builder.syn();
+        }
+        if self.rst {
+            builder = builder.rst();
+        }
+        if let Some(ack) = self.ack {
+            builder = builder.ack(ack);
+        }
+        let builder = builder.options(self.options).unwrap();
+        let mut packet = Vec::new();
+        builder.write(&mut packet, self.payload).unwrap();
+        packet
+    }
+}
+
+fn syn(seq: u32) -> Segment<'static> {
+    Segment { seq, syn: true, ..Segment::default() }
+}
+
+fn ack(seq: u32, ack: u32) -> Segment<'static> {
+    Segment { seq, ack: Some(ack), ..Segment::default() }
+}
+
+/// A segment the scripted peer received from the stack.
+struct Received {
+    seq: u32,
+    ack: u32,
+    syn: bool,
+    window: u16,
+    len: usize,
+    options: Vec<TcpOptionElement>,
+}
+
+fn receive(peer: &impl PacketDevice, within: Duration) -> Received {
+    let mut buf = [0u8; 1500];
+    let n = peer.recv(&mut buf, Some(within)).unwrap();
+    let iph = etherparse::Ipv4HeaderSlice::from_slice(&buf[..n]).unwrap();
+    let tcph = etherparse::TcpHeaderSlice::from_slice(&buf[iph.slice().len()..n]).unwrap();
+    Received {
+        seq: tcph.sequence_number(),
+        ack: tcph.acknowledgment_number(),
+        syn: tcph.syn(),
+        window: tcph.window_size(),
+        len: n - iph.slice().len() - tcph.slice().len(),
+        options: tcph.options_iterator().map(Result::unwrap).collect(),
+    }
+}
+
+/// Checks that the stack sends the peer nothing for `within`.
+fn assert_silent(peer: &impl PacketDevice, within: Duration) {
+    let err = peer.recv(&mut [0u8; 1500], Some(within)).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+}
+
+fn find_timestamp(options: &[TcpOptionElement]) -> Option<(u32, u32)> {
+    options.iter().find_map(|o| match *o {
+        TcpOptionElement::Timestamp(tsval, tsecr) => Some((tsval, tsecr)),
+        _ => None,
+    })
+}
+
+fn timestamp(received: &Received) -> (u32, u32) {
+    find_timestamp(&received.options).expect("segment carries a timestamp")
+}
+
+/// Completes a handshake that the scripted peer opens with `syn`, and returns the accepted
+/// stream along with the SYN-ACK.
+///
+/// The peer's ACK advertises the same window as its SYN, and carries the next timestamp if
+/// both SYNs carried one.
+fn handshake(scripted: &mut Scripted, syn: Segment) -> (TcpStream, Received) {
+    scripted.peer.send(&syn.build()).unwrap();
+    let syn_ack = receive(&scripted.peer, Duration::from_secs(1));
+    assert!(syn_ack.syn);
+    assert_eq!(syn_ack.ack, syn.seq + 1);
+    let ts: Vec<_> = find_timestamp(syn.options)
+        .zip(find_timestamp(&syn_ack.options))
+        .map(|((tsval, _), (echo, _))| TcpOptionElement::Timestamp(tsval + 1, echo))
+        .into_iter()
+        .collect();
+    let ack = Segment {
+        port: syn.port,
+        window: syn.window,
+        options: &ts,
+        ..ack(syn.seq + 1, syn_ack.seq + 1)
+    };
+    scripted.peer.send(&ack.build()).unwrap();
+    (scripted.listener.accept().unwrap(), syn_ack)
+}
+
+#[test]
+fn corrupted_segments_are_ignored() {
+    let s = scripted_server(Config::default());
+
+    let mut corrupted = syn(1000).build();
+    // the TCP checksum sits 16 bytes into the segment
+    corrupted[20 + 16] ^= 0xff;
+    s.peer.send(&corrupted).unwrap();
+    assert_silent(&s.peer, Duration::from_secs(5));
+
+    s.peer.send(&syn(1000).build()).unwrap();
+    let syn_ack = receive(&s.peer, Duration::from_secs(5));
+    assert!(syn_ack.syn);
+    assert_eq!(syn_ack.ack, 1001);
+}
+
+#[test]
+fn writes_that_fail_to_send_are_retransmitted() {
+    let mut s = scripted_server(Config::default());
+    let (mut stream, syn_ack) = handshake(&mut s, syn(1000));
+
+    // the link refuses the segment, but the data is buffered and write says so
+    let link = LinkConfig {
+        latency: Duration::ZERO,
+        ..LinkConfig::default()
+    };
+    s.net.set_config(LinkConfig { mtu: 100, ..link.clone() });
+    assert_eq!(stream.write(&payload(200)).unwrap(), 200);
+    s.net.set_config(link);
+
+    let retransmitted = receive(&s.peer, Duration::from_secs(5));
+    assert_eq!((retransmitted.seq, retransmitted.len), (syn_ack.seq + 1, 200));
+    s.peer.send(&ack(1001, syn_ack.seq + 201).build()).unwrap();
+    stream.flush().unwrap();
+}
+
+#[test]
+fn three_duplicate_acks_trigger_fast_retransmit() {
+    let mut s = scripted_server(Config::default());
+    let (mut stream, syn_ack) = handshake(&mut s, syn(1000));
+    stream.set_nodelay(true).unwrap();
+
+    // two segments go out, of which the peer pretends to have lost the first
+    stream.write_all(&payload(1000)).unwrap();
+    let first = receive(&s.peer, Duration::from_secs(1));
+    assert_eq!(first.seq, syn_ack.seq + 1);
+    let second = receive(&s.peer, Duration::from_secs(1));
+    assert_eq!(second.seq, first.seq + 536);
+    assert_eq!(second.len, 1000 - 536);
+
+    let start = s.net.now();
+    for _ in 0..3 {
+        s.peer.send(&ack(1001, first.seq).build()).unwrap();
+    }
+    let retransmitted = receive(&s.peer, Duration::from_secs(5));
+    assert_eq!((retransmitted.seq, retransmitted.len), (first.seq, 536));
+    // well before the retransmission timer would have fired
+    assert!(s.net.now() - start < Duration::from_millis(100));
+
+    s.peer.send(&ack(1001, first.seq + 1000).build()).unwrap();
+    stream.flush().unwrap();
+}
+
+#[test]
+fn a_timeout_resends_everything_that_was_lost() {
+    let mut s = scripted_server(Config::default());
+    let mss = [TcpOptionElement::MaximumSegmentSize(100)];
+    let (mut stream, syn_ack) = handshake(&mut s, Segment { options: &mss, ..syn(1000) });
+    let iss = syn_ack.seq;
+    stream.set_nodelay(true).unwrap();
+
+    // the whole window is lost
+    stream.write_all(&payload(400)).unwrap();
+    for i in 0..4 {
+        assert_eq!(receive(&s.peer, Duration::from_secs(1)).seq, iss + 1 + 100 * i);
+    }
+    let retransmitted = receive(&s.peer, Duration::from_secs(5));
+    assert_eq!((retransmitted.seq, retransmitted.len), (iss + 1, 100));
+
+    // each ACK brings the next lost segments along, without waiting for another timeout
+    let start = s.net.now();
+    s.peer.send(&ack(1001, iss + 101).build()).unwrap();
+    let mut resent = Vec::new();
+    while resent.len() < 3 {
+        let segment = receive(&s.peer, Duration::from_millis(100));
+        resent.push(segment.seq);
+        s.peer.send(&ack(1001, segment.seq + segment.len as u32).build()).unwrap();
+    }
+    assert_eq!(resent, [iss + 101, iss + 201, iss + 301]);
+    assert!(s.net.now() - start < Duration::from_millis(100));
+    stream.flush().unwrap();
+}
+
+#[test]
+fn segments_fit_the_negotiated_mss() {
+    let link = LinkConfig {
+        latency: Duration::ZERO,
+        mtu: 1000,
+        ..LinkConfig::default()
+    };
+    let mut s = scripted_server_on(link, Config::default());
+    let options = [TcpOptionElement::MaximumSegmentSize(300)];
+    let (mut stream, syn_ack) = handshake(&mut s, Segment { options: &options, ..syn(1000) });
+    // our MSS follows from the MTU
+    assert!(syn_ack.options.contains(&TcpOptionElement::MaximumSegmentSize(960)));
+    stream.set_nodelay(true).unwrap();
+
+    // but we send no more than the peer asked for
+    stream.write_all(&payload(700)).unwrap();
+    let lens: Vec<_> = (0..3).map(|_| receive(&s.peer, Duration::from_secs(1)).len).collect();
+    assert_eq!(lens, [300, 300, 100]);
+
+    s.peer.send(&ack(1001, syn_ack.seq + 701).build()).unwrap();
+    stream.flush().unwrap();
+}
+
+#[test]
+fn tiny_peer_mss_is_raised_to_a_floor() {
+    let mut s = scripted_server(Config::default());
+    let options = [
+        TcpOptionElement::MaximumSegmentSize(8),
+        TcpOptionElement::SelectiveAcknowledgementPermitted,
+        TcpOptionElement::Timestamp(100, 0),
+    ];
+    let (mut stream, syn_ack) = handshake(&mut s, Segment { options: &options, ..syn(1000) });
+    stream.set_nodelay(true).unwrap();
+
+    // segments of 88 bytes, less the 12 the timestamp takes up
+    stream.write_all(&payload(200)).unwrap();
+    let segments: Vec<_> = (0..3).map(|_| receive(&s.peer, Duration::from_secs(1))).collect();
+    let lens: Vec<_> = segments.iter().map(|r| r.len).collect();
+    assert_eq!(lens, [76, 76, 48]);
+
+    let ts = [TcpOptionElement::Timestamp(102, timestamp(&segments[2]).0)];
+    s.peer.send(&Segment { options: &ts, ..ack(1001, syn_ack.seq + 201) }.build()).unwrap();
+    stream.flush().unwrap();
+}
+
+#[test]
+fn peer_windows_are_scaled() {
+    let mut s = scripted_server(Config::default());
+    let options = [TcpOptionElement::WindowScale(2)];
+    // 100 << 2 bytes, once the handshake is done
+    let (mut stream, syn_ack) =
+        handshake(&mut s, Segment { options: &options, window: 100, ..syn(1000) });
+    assert!(syn_ack
+        .options
+        .iter()
+        .any(|o| matches!(o, TcpOptionElement::WindowScale(_))));
+    // let the tail out without waiting for the rest to be acknowledged
+    stream.set_nodelay(true).unwrap();
+
+    stream.write_all(&payload(1000)).unwrap();
+    let first = receive(&s.peer, Duration::from_secs(1));
+    assert_eq!(first.len, 400);
+    assert_silent(&s.peer, Duration::from_millis(100));
+
+    // 151 << 2 bytes make room for the remaining 600
+    let window = Segment { window: 151, ..ack(1001, first.seq + 400) };
+    s.peer.send(&window.build()).unwrap();
+    assert_eq!(receive(&s.peer, Duration::from_secs(1)).len, 536);
+    assert_eq!(receive(&s.peer, Duration::from_secs(1)).len, 64);
+
+    s.peer.send(&ack(1001, first.seq + 1000).build()).unwrap();
+    stream.flush().unwrap();
+}
+
+#[test]
+fn timestamps_are_echoed_and_protect_against_old_segments() {
+    let mut s = scripted_server(Config::default());
+    let ts = [TcpOptionElement::Timestamp(100, 0)];
+    let (mut stream, syn_ack) = handshake(&mut s, Segment { options: &ts, ..syn(1000) });
+    let (tsval, tsecr) = timestamp(&syn_ack);
+    assert_eq!(tsecr, 100);
+
+    let ts = [TcpOptionElement::Timestamp(102, tsval)];
+    let data = Segment { options: &ts, payload: b"hello", ..ack(1001, syn_ack.seq + 1) };
+    s.peer.send(&data.build()).unwrap();
+    let reply = receive(&s.peer, Duration::from_secs(1));
+    assert_eq!(reply.ack, 1006);
+    assert_eq!(timestamp(&reply).1, 102);
+
+    // an older timestamp marks the segment as a stale duplicate
+    let ts = [TcpOptionElement::Timestamp(50, tsval)];
+    let stale = Segment { options: &ts, payload: b"stale", ..ack(1006, syn_ack.seq + 1) };
+    s.peer.send(&stale.build()).unwrap();
+    let reply = receive(&s.peer, Duration::from_secs(1));
+    assert_eq!(reply.ack, 1006);
+    assert_eq!(timestamp(&reply).1, 102);
+
+    let ts = [TcpOptionElement::Timestamp(103, tsval)];
+    let fresh = Segment { options: &ts, payload: b"fresh", ..ack(1006, syn_ack.seq + 1) };
+    s.peer.send(&fresh.build()).unwrap();
+    let reply = receive(&s.peer, Duration::from_secs(1));
+    assert_eq!(reply.ack, 1011);
+    assert_eq!(timestamp(&reply).1, 103);
+
+    let mut buf = [0u8; 10];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hellofresh");
+}
+
+fn sack_blocks(received: &Received) -> Vec<(u32, u32)> {
+    received
+        .options
+        .iter()
+        .find_map(|o| match *o {
+            TcpOptionElement::SelectiveAcknowledgement(first, rest) => {
+                Some(std::iter::once(first).chain(rest.iter().flatten().copied()).collect())
+            }
+            _ => None,
+        })
+        .unwrap_or_default()
+}
+
+#[test]
+fn out_of_order_data_is_selectively_acknowledged() {
+    let mut s = scripted_server(Config::default());
+    let options = [TcpOptionElement::SelectiveAcknowledgementPermitted];
+    let (mut stream, syn_ack) = handshake(&mut s, Segment { options: &options, ..syn(1000) });
+    assert!(syn_ack
+        .options
+        .iter()
+        .any(|o| matches!(o, TcpOptionElement::SelectiveAcknowledgementPermitted)));
+
+    let early = Segment { payload: b"world", ..ack(1006, syn_ack.seq + 1) };
+    s.peer.send(&early.build()).unwrap();
+    let reply = receive(&s.peer, Duration::from_secs(1));
+    assert_eq!(reply.ack, 1001);
+    assert_eq!(sack_blocks(&reply), vec![(1006, 1011)]);
+
+    let gap = Segment { payload: b"hello", ..ack(1001, syn_ack.seq + 1) };
+    s.peer.send(&gap.build()).unwrap();
+    let reply = receive(&s.peer, Duration::from_secs(1));
+    assert_eq!(reply.ack, 1011);
+    assert!(sack_blocks(&reply).is_empty());
+
+    let mut buf = [0u8; 10];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"helloworld");
+}
+
+#[test]
+fn only_the_holes_are_retransmitted() {
+    let mut s = scripted_server(Config::default());
+    let options = [
+        TcpOptionElement::MaximumSegmentSize(100),
+        TcpOptionElement::SelectiveAcknowledgementPermitted,
+    ];
+    let (mut stream, syn_ack) = handshake(&mut s, Segment { options: &options, ..syn(1000) });
+    let start = syn_ack.seq + 1;
+
+    // the initial window lets out four segments; the first and the third get lost
+    stream.write_all(&payload(1000)).unwrap();
+    for i in 0..4 {
+        assert_eq!(receive(&s.peer, Duration::from_secs(1)).seq, start + i * 100);
+    }
+    let sacks = [
+        [TcpOptionElement::SelectiveAcknowledgement((start + 100, start + 200), [None; 3])],
+        [TcpOptionElement::SelectiveAcknowledgement(
+            (start + 300, start + 400),
+            [Some((start + 100, start + 200)), None, None],
+        )],
+    ];
+    let mut retransmitted = Vec::new();
+    for sack in [&sacks[0], &sacks[1], &sacks[1], &sacks[1]] {
+        s.peer.send(&Segment { options: sack, ..ack(1001, start) }.build()).unwrap();
+        let mut buf = [0u8; 1500];
+        while let Ok(n) = s.peer.recv(&mut buf, Some(Duration::from_millis(100))) {
+            let iph = etherparse::Ipv4HeaderSlice::from_slice(&buf[..n]).unwrap();
+            let tcph = etherparse::TcpHeaderSlice::from_slice(&buf[iph.slice().len()..n]).unwrap();
+            let seq = tcph.sequence_number();
+            if seq.wrapping_sub(start) < 400 {
+                retransmitted.push(seq.wrapping_sub(start));
+            }
+        }
+    }
+    assert_eq!(retransmitted, vec![0, 200]);
+}
+
+/// Hands out the same initial sequence number to every connection.
+struct FixedIss(u32);
+
+impl IssGenerator for FixedIss {
+    fn iss(&mut self, _: SocketAddrV4, _: SocketAddrV4, _: Instant) -> u32 {
+        self.0
+    }
+}
+
+/// The ISS a fresh server on its own network answers `syn(1000)` with.
+fn server_iss(generator: Option<FixedIss>) -> u32 {
+    let mut s = scripted_server(Config::default());
+    if let Some(generator) = generator {
+        s.server.set_iss_generator(generator);
+    }
+    s.peer.send(&syn(1000).build()).unwrap();
+    let syn_ack = receive(&s.peer, Duration::from_secs(1));
+    assert!(syn_ack.syn);
+    syn_ack.seq
+}
+
+#[test]
+fn initial_sequence_numbers_are_keyed_per_interface() {
+    // the same connection gets a different ISS from every interface's key
+    assert_ne!(server_iss(None), server_iss(None));
+    assert_eq!(server_iss(Some(FixedIss(0xdead_beef))), 0xdead_beef);
+}
+
+#[test]
+fn syn_cookies_answer_syns_beyond_the_backlog() {
+    let mut s = scripted_server(Config {
+        syn_backlog: 1,
+        ..Config::default()
+    });
+    let options = [TcpOptionElement::WindowScale(2)];
+    let offers_wscale =
+        |r: &Received| r.options.iter().any(|o| matches!(o, TcpOptionElement::WindowScale(_)));
+
+    // the first SYN fills the backlog and gets a connection of its own
+    s.peer.send(&Segment { options: &options, ..syn(1000) }.build()).unwrap();
+    let first = receive(&s.peer, Duration::from_secs(1));
+    assert!(first.syn && offers_wscale(&first));
+
+    // the second is answered with a cookie, which can't remember the window scale
+    s.peer.send(&Segment { port: 40001, options: &options, ..syn(5000) }.build()).unwrap();
+    let cookie = receive(&s.peer, Duration::from_secs(1));
+    assert!(cookie.syn && !offers_wscale(&cookie));
+    assert_eq!(cookie.ack, 5001);
+
+    // a forged cookie doesn't get a connection, but the real one does
+    let forged = Segment { port: 40001, ..ack(5001, cookie.seq ^ 1 << 20) };
+    s.peer.send(&forged.build()).unwrap();
+    receive(&s.peer, Duration::from_secs(1));
+    let returned = Segment { port: 40001, ..ack(5001, cookie.seq.wrapping_add(1)) };
+    s.peer.send(&returned.build()).unwrap();
+    // the first connection is still half-open, so accept skips it
+    let mut stream = s.listener.accept().unwrap();
+    stream.write_all(b"hello").unwrap();
+    let data = receive(&s.peer, Duration::from_secs(1));
+    assert_eq!((data.seq, data.ack, data.len), (cookie.seq.wrapping_add(1), 5001, 5));
+
+    s.peer.send(&Segment { port: 40001, ..ack(5001, data.seq.wrapping_add(5)) }.build()).unwrap();
+    stream.flush().unwrap();
+}
+
+#[test]
+fn listeners_queue_a_bounded_number_of_connections() {
+    let mut s = scripted_server(Config {
+        accept_backlog: 2,
+        ..Config::default()
+    });
+
+    // a half-open connection takes no room in the queue
+    s.peer.send(&Segment { port: 40000, ..syn(1000) }.build()).unwrap();
+    assert!(receive(&s.peer, Duration::from_secs(1)).syn);
+    for port in [40001, 40002] {
+        s.peer.send(&Segment { port, ..syn(1000) }.build()).unwrap();
+        let syn_ack = receive(&s.peer, Duration::from_secs(1));
+        assert!(syn_ack.syn);
+        s.peer.send(&Segment { port, ..ack(1001, syn_ack.seq + 1) }.build()).unwrap();
+    }
+    // but two established ones fill it
+    s.peer.send(&Segment { port: 40003, ..syn(1000) }.build()).unwrap();
+    assert_silent(&s.peer, Duration::from_millis(100));
+
+    // accepting one makes room for the retransmitted SYN
+    let _first = s.listener.accept().unwrap();
+    s.peer.send(&Segment { port: 40003, ..syn(1000) }.build()).unwrap();
+    assert!(receive(&s.peer, Duration::from_secs(1)).syn);
+}
+
+#[test]
+fn half_open_connections_are_not_accepted() {
+    let mut s = scripted_server(Config::default());
+
+    // the first peer never completes its handshake
+    s.peer.send(&syn(1000).build()).unwrap();
+    assert!(receive(&s.peer, Duration::from_secs(1)).syn);
+
+    let (mut stream, syn_ack) = handshake(&mut s, Segment { port: 40001, ..syn(5000) });
+    stream.write_all(b"hello").unwrap();
+    let data = receive(&s.peer, Duration::from_secs(1));
+    assert_eq!((data.seq, data.ack, data.len), (syn_ack.seq + 1, 5001, 5));
+}
+
+#[test]
+fn connections_reset_before_accept_are_dropped() {
+    let mut s = scripted_server(Config::default());
+
+    // the peer gives up on its first connection before the handshake completes
+    s.peer.send(&syn(1000).build()).unwrap();
+    assert!(receive(&s.peer, Duration::from_secs(1)).syn);
+    s.peer.send(&Segment { rst: true, ..syn(1001) }.build()).unwrap();
+
+    // so accept hands out the next one instead
+    let (mut stream, syn_ack) = handshake(&mut s, Segment { port: 40001, ..syn(5000) });
+    stream.write_all(b"hello").unwrap();
+    let data = receive(&s.peer, Duration::from_secs(1));
+    assert_eq!((data.seq, data.ack, data.len), (syn_ack.seq + 1, 5001, 5));
+}
+
+#[test]
+fn dropped_streams_give_up_on_a_peer_that_never_closes() {
+    let mut s = scripted_server(Config {
+        fin_timeout: Duration::from_secs(10),
+        ..Config::default()
+    });
+    let (stream, syn_ack) = handshake(&mut s, syn(1000));
+
+    // the peer acknowledges our FIN, but never sends its own
+    drop(stream);
+    let fin = receive(&s.peer, Duration::from_secs(1));
+    assert_eq!(fin.seq, syn_ack.seq + 1);
+    s.peer.send(&ack(1001, fin.seq + 1).build()).unwrap();
+    assert_silent(&s.peer, Duration::from_secs(11));
+
+    // by now the connection is gone, so a new SYN on the same port pair opens another
+    s.peer.send(&syn(5000).build()).unwrap();
+    let reply = receive(&s.peer, Duration::from_secs(1));
+    assert!(reply.syn);
+    assert_eq!(reply.ack, 5001);
+}
+
+#[test]
+fn small_writes_are_coalesced_until_acknowledged() {
+    let mut s = scripted_server(Config::default());
+    let (mut stream, _) = handshake(&mut s, syn(1000));
+    assert!(!stream.nodelay().unwrap());
+
+    // nothing is outstanding, so the first small write goes out right away
+    stream.write_all(b"a").unwrap();
+    let first = receive(&s.peer, Duration::from_secs(1));
+    assert_eq!(first.len, 1);
+    // the next ones wait for its ACK
+    stream.write_all(b"b").unwrap();
+    stream.write_all(b"c").unwrap();
+    assert_silent(&s.peer, Duration::from_millis(100));
+    s.peer.send(&ack(1001, first.seq + 1).build()).unwrap();
+    let second = receive(&s.peer, Duration::from_secs(1));
+    assert_eq!((second.seq, second.len), (first.seq + 1, 2));
+
+    // without Nagle, small writes don't wait
+    stream.set_nodelay(true).unwrap();
+    assert!(stream.nodelay().unwrap());
+    stream.write_all(b"d").unwrap();
+    let third = receive(&s.peer, Duration::from_secs(1));
+    assert_eq!((third.seq, third.len), (first.seq + 3, 1));
+
+    s.peer.send(&ack(1001, first.seq + 4).build()).unwrap();
+    stream.flush().unwrap();
+}
+
+#[test]
+fn full_segments_with_timestamps_are_not_held_back() {
+    let mut s = scripted_server(Config::default());
+    let options = [
+        TcpOptionElement::MaximumSegmentSize(536),
+        TcpOptionElement::Timestamp(100, 0),
+    ];
+    let (mut stream, syn_ack) = handshake(&mut s, Segment { options: &options, ..syn(1000) });
+
+    // the timestamp leaves room for 524 bytes in each segment, so neither write is small
+    stream.write_all(&payload(524)).unwrap();
+    stream.write_all(&payload(524)).unwrap();
+    let first = receive(&s.peer, Duration::from_secs(1));
+    let second = receive(&s.peer, Duration::from_millis(1));
+    assert_eq!((first.seq, first.len), (syn_ack.seq + 1, 524));
+    assert_eq!((second.seq, second.len), (syn_ack.seq + 525, 524));
+}
+
+#[test]
+fn acks_are_delayed_after_the_quick_start() {
+    let mut s = scripted_server(Config {
+        ack_delay: Duration::from_millis(100),
+        ..Config::default()
+    });
+    let (mut stream, syn_ack) = handshake(&mut s, syn(1000));
+    let iss = syn_ack.seq;
+
+    // right after the handshake, every segment is acknowledged at once
+    let mut seq = 1001;
+    for _ in 0..8 {
+        s.peer.send(&Segment { payload: b"x", ..ack(seq, iss + 1) }.build()).unwrap();
+        seq += 1;
+        assert_eq!(receive(&s.peer, Duration::from_millis(1)).ack, seq);
+    }
+
+    // then the ACK waits for the delay to run out
+    let start = s.net.now();
+    s.peer.send(&Segment { payload: b"x", ..ack(seq, iss + 1) }.build()).unwrap();
+    seq += 1;
+    let delayed = receive(&s.peer, Duration::from_secs(1));
+    assert_eq!(delayed.ack, seq);
+    assert_eq!(s.net.now() - start, Duration::from_millis(100));
+
+    // or rides along with data we send before then
+    s.peer.send(&Segment { payload: b"x", ..ack(seq, iss + 1) }.build()).unwrap();
+    seq += 1;
+    assert_silent(&s.peer, Duration::from_millis(50));
+    stream.write_all(b"reply").unwrap();
+    let reply = receive(&s.peer, Duration::from_secs(1));
+    assert_eq!((reply.ack, reply.len), (seq, 5));
+    assert_silent(&s.peer, Duration::from_millis(200));
+
+    // out-of-order data is acknowledged at once
+    s.peer.send(&Segment { payload: b"x", ..ack(seq + 1, iss + 6) }.build()).unwrap();
+    assert_eq!(receive(&s.peer, Duration::from_millis(1)).ack, seq);
+}
+
+#[test]
+fn keepalive_gives_up_on_a_silent_peer() {
+    let mut s = scripted_server(Config {
+        keepalive_interval: Duration::from_secs(1),
+        keepalive_probes: 3,
+        ..Config::default()
+    });
+    let (mut stream, syn_ack) = handshake(&mut s, syn(1000));
+    assert_eq!(stream.keepalive().unwrap(), None);
+    stream.set_keepalive(Some(Duration::from_secs(10))).unwrap();
+    assert_eq!(stream.keepalive().unwrap(), Some(Duration::from_secs(10)));
+
+    // a probe repeats the last byte we sent; answering it restarts the idle time
+    let start = s.net.now();
+    let probe = receive(&s.peer, Duration::from_secs(20));
+    assert_eq!((probe.seq, probe.ack, probe.len), (syn_ack.seq, 1001, 0));
+    assert_eq!(s.net.now() - start, Duration::from_secs(10));
+    s.peer.send(&ack(1001, syn_ack.seq + 1).build()).unwrap();
+
+    // then the peer goes quiet
+    let start = s.net.now();
+    for _ in 0..3 {
+        assert_eq!(receive(&s.peer, Duration::from_secs(20)).seq, syn_ack.seq);
+    }
+    assert_eq!(s.net.now() - start, Duration::from_secs(12));
+    assert_silent(&s.peer, Duration::from_secs(20));
+
+    assert_eq!(stream.read(&mut [0u8; 1]).unwrap_err().kind(), io::ErrorKind::TimedOut);
+    assert_eq!(stream.write(b"x").unwrap_err().kind(), io::ErrorKind::TimedOut);
+}
+
+#[test]
+fn closed_windows_are_probed_until_they_open() {
+    let mut s = scripted_server(Config::default());
+    let (mut stream, syn_ack) = handshake(&mut s, Segment { window: 0, ..syn(1000) });
+    let closed = Segment { window: 0, ..ack(1001, syn_ack.seq + 1) };
+
+    // nothing goes out into a closed window; probes ask about it at growing intervals
+    stream.write_all(b"hello").unwrap();
+    let mut start = s.net.now();
+    for interval in [1, 2, 4] {
+        let probe = receive(&s.peer, Duration::from_secs(10));
+        assert_eq!((probe.seq, probe.len), (syn_ack.seq, 0));
+        assert_eq!(s.net.now() - start, Duration::from_secs(interval));
+        start = s.net.now();
+        s.peer.send(&closed.build()).unwrap();
+    }
+
+    // a window update lets the data out
+    s.peer.send(&ack(1001, syn_ack.seq + 1).build()).unwrap();
+    let data = receive(&s.peer, Duration::from_secs(1));
+    assert_eq!((data.seq, data.len), (syn_ack.seq + 1, 5));
+    s.peer.send(&ack(1001, syn_ack.seq + 6).build()).unwrap();
+    stream.flush().unwrap();
+    assert_silent(&s.peer, Duration::from_secs(10));
+}
+
+#[test]
+fn the_receive_window_follows_the_buffer() {
+    let mut s = scripted_server(Config {
+        recv_buffer: 2000,
+        ..Config::default()
+    });
+    let (mut stream, syn_ack) = handshake(&mut s, syn(1000));
+    assert_eq!(syn_ack.window, 2000);
+
+    // unread data closes the window
+    let chunk = payload(500);
+    let mut seq = 1001;
+    for window in [1500, 1000, 500, 0] {
+        s.peer.send(&Segment { payload: &chunk, ..ack(seq, syn_ack.seq + 1) }.build()).unwrap();
+        seq += 500;
+        let reply = receive(&s.peer, Duration::from_secs(1));
+        assert_eq!((reply.ack, reply.window), (seq, window));
+    }
+    s.peer.send(&Segment { payload: b"x", ..ack(seq, syn_ack.seq + 1) }.build()).unwrap();
+    let reply = receive(&s.peer, Duration::from_secs(1));
+    assert_eq!((reply.ack, reply.window), (seq, 0));
+
+    // reading a little isn't worth a window update, but reading an MSS is
+    let mut buf = [0u8; 536];
+    stream.read_exact(&mut buf[..100]).unwrap();
+    assert_silent(&s.peer, Duration::from_secs(1));
+    stream.read_exact(&mut buf[..436]).unwrap();
+    let update = receive(&s.peer, Duration::from_secs(1));
+    assert_eq!((update.ack, update.window), (seq, 536));
+}